@@ -1,4 +1,6 @@
 pub mod types;
 pub mod utils;
 pub mod services;
-pub mod integration;
\ No newline at end of file
+pub mod integration;
+#[cfg(feature = "test-util")]
+pub mod test_util;
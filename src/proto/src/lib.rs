@@ -28,5 +28,9 @@ pub mod user_online_point {
     include!(concat!(env!("OUT_DIR"), "/subnet_dpn.user_online_point.rs"));
 }
 
+pub mod peer_stats {
+    include!(concat!(env!("OUT_DIR"), "/subnet_dpn.peer_stats.rs"));
+}
+
 #[cfg(test)]
 mod tests {}
@@ -0,0 +1,245 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use futures_util::StreamExt as _;
+use log::warn;
+
+use super::redis::{DPNRedisKey, RedisService};
+
+/// a failed load keeps every flag disabled for this long before redis is read again
+pub const LOAD_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// runtime toggles read from the `feature_flags` redis hash (name -> bool)
+/// flags are cached after first read, `watch` drops the cache whenever a flag is changed
+#[derive(Debug)]
+pub struct FeatureFlags {
+    redis: Arc<RedisService>,
+    cache: FlagCache,
+}
+
+impl FeatureFlags {
+    pub fn new(redis: Arc<RedisService>) -> Self {
+        Self {
+            redis,
+            cache: FlagCache::default(),
+        }
+    }
+
+    /// unknown or malformed flags are disabled, so are all flags while redis cannot be read
+    pub async fn is_enabled(&self, name: &str) -> bool {
+        if let Some(enabled) = self.cache.get(name, Instant::now()) {
+            return enabled;
+        }
+        let generation = self.cache.generation();
+        let snapshot = match self.redis.clone().get_feature_flags().await {
+            Ok(raw) => Snapshot::loaded(parse_flags(raw)),
+            Err(e) => {
+                warn!("feature flags: load failed, {} is disabled err={}", name, e);
+                Snapshot::failed(Instant::now() + LOAD_RETRY_INTERVAL)
+            }
+        };
+        let enabled = snapshot.is_enabled(name);
+        self.cache.store(generation, snapshot);
+        enabled
+    }
+
+    pub fn invalidate(&self) {
+        self.cache.invalidate();
+    }
+
+    /// invalidate cache on every message of feature flags channel
+    /// returns an error when the subscription ends, callers are expected to resubscribe
+    pub async fn watch(self: Arc<Self>) -> Result<()> {
+        let changes = self
+            .redis
+            .clone()
+            .subscribe_raw(DPNRedisKey::get_feature_flags_chan())
+            .await
+            .map_err(|e| anyhow!("feature flags: subscribe failed err={}", e))?;
+        let mut changes = Box::pin(changes);
+        while let Some(change) = changes.next().await {
+            if let Err(e) = change {
+                // changes may be missed until resubscribed, the cache cannot be trusted anymore
+                self.invalidate();
+                return Err(anyhow!("feature flags: subscription failed err={}", e));
+            }
+            self.invalidate();
+        }
+        // changes may be missed until resubscribed, the cache cannot be trusted anymore
+        self.invalidate();
+        Err(anyhow!("feature flags: subscription ended"))
+    }
+}
+
+/// decode raw flag values, values other than `true`/`false` are skipped (disabled)
+fn parse_flags(raw: HashMap<String, String>) -> HashMap<String, bool> {
+    raw.into_iter()
+        .filter_map(|(name, value)| match serde_json::from_str::<bool>(&value) {
+            Ok(enabled) => Some((name, enabled)),
+            Err(e) => {
+                warn!(
+                    "feature flags: invalid value, {} is disabled value={} err={}",
+                    name, value, e
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Snapshot {
+    flags: HashMap<String, bool>,
+    /// set for a failed load, redis is read again once it is reached
+    retry_at: Option<Instant>,
+}
+
+impl Snapshot {
+    fn loaded(flags: HashMap<String, bool>) -> Self {
+        Self {
+            flags,
+            retry_at: None,
+        }
+    }
+
+    fn failed(retry_at: Instant) -> Self {
+        Self {
+            flags: HashMap::new(),
+            retry_at: Some(retry_at),
+        }
+    }
+
+    fn is_enabled(&self, name: &str) -> bool {
+        self.flags.get(name).copied().unwrap_or(false)
+    }
+}
+
+/// every invalidation bumps the generation, a load started before it is not stored
+/// so a slow load cannot bring back the flags that were just invalidated
+#[derive(Debug, Default)]
+struct FlagCache {
+    snapshot: RwLock<Option<Snapshot>>,
+    generation: AtomicU64,
+}
+
+impl FlagCache {
+    /// None when flags must be (re)loaded
+    fn get(&self, name: &str, now: Instant) -> Option<bool> {
+        let snapshot = self.snapshot.read().unwrap();
+        let snapshot = snapshot.as_ref()?;
+        if snapshot.retry_at.is_some_and(|retry_at| now >= retry_at) {
+            return None;
+        }
+        Some(snapshot.is_enabled(name))
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// store `snapshot` loaded at `generation`, dropped when invalidated since
+    fn store(&self, generation: u64, snapshot: Snapshot) {
+        let mut current = self.snapshot.write().unwrap();
+        if self.generation.load(Ordering::Acquire) == generation {
+            *current = Some(snapshot);
+        }
+    }
+
+    fn invalidate(&self) {
+        let mut current = self.snapshot.write().unwrap();
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        *current = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::redis::tests::test_redis;
+
+    #[test]
+    fn test_parse_flags() {
+        let raw: HashMap<String, String> = [
+            ("compression", "true"),
+            ("proto_codec", "false"),
+            ("typo", "yes"),
+            ("number", "1"),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_owned(), value.to_owned()))
+        .collect();
+        let flags = parse_flags(raw);
+        assert_eq!(
+            flags,
+            HashMap::from([
+                ("compression".to_owned(), true),
+                ("proto_codec".to_owned(), false)
+            ])
+        );
+    }
+
+    #[test]
+    fn test_flag_cache_drops_load_started_before_invalidate() {
+        let cache = FlagCache::default();
+        let now = Instant::now();
+        assert_eq!(cache.get("compression", now), None);
+
+        let generation = cache.generation();
+        cache.invalidate();
+        let stale = HashMap::from([("compression".to_owned(), true)]);
+        cache.store(generation, Snapshot::loaded(stale));
+        assert_eq!(cache.get("compression", now), None);
+
+        let fresh = HashMap::from([("compression".to_owned(), false)]);
+        cache.store(cache.generation(), Snapshot::loaded(fresh));
+        assert_eq!(cache.get("compression", now), Some(false));
+        assert_eq!(cache.get("unknown", now), Some(false));
+    }
+
+    #[test]
+    fn test_flag_cache_retries_failed_load() {
+        let cache = FlagCache::default();
+        let now = Instant::now();
+        cache.store(
+            cache.generation(),
+            Snapshot::failed(now + LOAD_RETRY_INTERVAL),
+        );
+        // failure is cached, every flag is disabled without reading redis
+        assert_eq!(cache.get("compression", now), Some(false));
+        assert_eq!(cache.get("compression", now + LOAD_RETRY_INTERVAL), None);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_is_enabled() {
+        let redis = test_redis().await;
+        let flags = FeatureFlags::new(redis.clone());
+        let name = "test_is_enabled".to_owned();
+        redis
+            .clone()
+            .set_feature_flag(name.clone(), true)
+            .await
+            .unwrap();
+        assert!(flags.is_enabled(&name).await);
+
+        redis
+            .clone()
+            .set_feature_flag(name.clone(), false)
+            .await
+            .unwrap();
+        // cached until invalidated
+        assert!(flags.is_enabled(&name).await);
+        flags.invalidate();
+        assert!(!flags.is_enabled(&name).await);
+        redis
+            .hdel(DPNRedisKey::get_feature_flags_k(), name)
+            .unwrap();
+    }
+}
@@ -1,9 +1,47 @@
-use std::{net::IpAddr, sync::Arc};
+use std::{fmt::Debug, net::IpAddr, sync::Arc};
 
 use anyhow::{anyhow, Result};
 use maxminddb::{geoip2, Reader};
+use mockall::automock;
 
-use crate::types::geo::{City, Continent, Country, Geo, Location};
+use crate::types::{
+    connection::{GeoMismatch, PeernodeInfo},
+    geo::{City, Continent, Country, Geo, Location},
+};
+
+/// ip to geo lookup, implemented by the mmdb backed `GeoService`
+/// use `MockGeoResolver` in tests
+#[automock]
+pub trait GeoResolver: Debug + Send + Sync + 'static {
+    fn resolve(&self, ip_addr: IpAddr) -> Option<Geo>;
+}
+
+/// country geoname id of `ip_addr`, None when it is not an ip or cannot be resolved
+pub fn resolve_country_geoname_id(resolver: &dyn GeoResolver, ip_addr: &str) -> Option<u32> {
+    let ip_addr: IpAddr = ip_addr.parse().ok()?;
+    resolver.resolve(ip_addr)?.country?.geoname_id
+}
+
+/// check the country claimed by peer against the country of its ip, see `PeernodeInfo::verify_country`
+/// an ip that cannot be resolved is a mismatch
+pub fn verify_peer_country(
+    resolver: &dyn GeoResolver,
+    info: &PeernodeInfo,
+    tolerated: &[u32],
+) -> Result<(), GeoMismatch> {
+    let observed = info
+        .ip_addr
+        .parse::<IpAddr>()
+        .ok()
+        .and_then(|ip_addr| resolver.resolve(ip_addr));
+    match observed {
+        Some(observed) => info.verify_country(&observed, tolerated),
+        None => Err(GeoMismatch {
+            claimed: info.country_geoname_id,
+            observed: None,
+        }),
+    }
+}
 
 #[derive(Debug)]
 pub struct GeoService {
@@ -22,7 +60,10 @@ impl GeoService {
             .clone()
             .parse()
             .map_err(|e| anyhow!("parse ip addr failed err={}", e))?;
+        self.lookup(ip_addr)
+    }
 
+    fn lookup(&self, ip_addr: IpAddr) -> Result<Geo> {
         let geo = self.reader.lookup::<geoip2::City>(ip_addr).map_err(|e| {
             anyhow!(
                 "no mmdb record for ip_addr={} err={}",
@@ -86,3 +127,81 @@ impl GeoService {
         })
     }
 }
+
+impl GeoResolver for GeoService {
+    fn resolve(&self, ip_addr: IpAddr) -> Option<Geo> {
+        self.lookup(ip_addr).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_country_geoname_id() {
+        let mut resolver = MockGeoResolver::new();
+        resolver
+            .expect_resolve()
+            .withf(|ip_addr| *ip_addr == "1.2.3.4".parse::<IpAddr>().unwrap())
+            .returning(|_| {
+                Some(Geo {
+                    country: Some(Country {
+                        geoname_id: Some(1562822),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                })
+            });
+
+        assert_eq!(
+            resolve_country_geoname_id(&resolver, "1.2.3.4"),
+            Some(1562822)
+        );
+        // wallet address is not an ip, resolver is not called
+        assert_eq!(resolve_country_geoname_id(&resolver, "0xpeer"), None);
+    }
+
+    #[test]
+    fn test_verify_peer_country() {
+        let mut resolver = MockGeoResolver::new();
+        resolver
+            .expect_resolve()
+            .withf(|ip_addr| *ip_addr == "1.2.3.4".parse::<IpAddr>().unwrap())
+            .returning(|_| {
+                Some(Geo {
+                    country: Some(Country {
+                        geoname_id: Some(1562822),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                })
+            });
+        resolver.expect_resolve().returning(|_| None);
+        let peer = |ip_addr: &str, country_geoname_id: u32| PeernodeInfo {
+            peer_id: "peer_id".to_owned(),
+            ip_addr: ip_addr.to_owned(),
+            throughput: 0.0,
+            rate_per_kb: 0,
+            rate_per_second: 0,
+            city_geoname_id: 0,
+            country_geoname_id,
+        };
+
+        assert!(verify_peer_country(&resolver, &peer("1.2.3.4", 1562822), &[]).is_ok());
+        assert_eq!(
+            verify_peer_country(&resolver, &peer("1.2.3.4", 1880251), &[]),
+            Err(GeoMismatch {
+                claimed: 1880251,
+                observed: Some(1562822),
+            })
+        );
+        assert_eq!(
+            verify_peer_country(&resolver, &peer("5.6.7.8", 1880251), &[]),
+            Err(GeoMismatch {
+                claimed: 1880251,
+                observed: None,
+            })
+        );
+    }
+}
@@ -1,3 +1,6 @@
+pub mod feature_flags;
 pub mod geo;
+pub mod proxy_acc_cache;
 pub mod redis;
+pub mod speed_test_throttle;
 pub mod types;
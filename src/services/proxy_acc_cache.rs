@@ -0,0 +1,142 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{anyhow, Result};
+use futures_util::StreamExt as _;
+use log::warn;
+
+use super::{
+    redis::{DPNRedisKey, RedisService},
+    types::ProxyAccChanged,
+};
+use crate::types::connection::ProxyAccData;
+
+/// local copy of proxy accs kept up to date from the proxy acc channel
+#[derive(Debug)]
+pub struct ProxyAccCache {
+    redis: Arc<RedisService>,
+    /// proxy_acc_id -> proxy acc
+    accs: RwLock<HashMap<String, ProxyAccData>>,
+}
+
+impl ProxyAccCache {
+    pub fn new(redis: Arc<RedisService>) -> Self {
+        Self {
+            redis,
+            accs: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<ProxyAccData> {
+        self.accs.read().unwrap().get(id).cloned()
+    }
+
+    /// basic auth username of a proxy acc is its id
+    pub fn get_by_username(&self, username: &str) -> Option<ProxyAccData> {
+        self.get(username)
+    }
+
+    pub fn len(&self) -> usize {
+        self.accs.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// replace cache with all proxy accs in redis
+    pub async fn reload(&self) -> Result<()> {
+        let accs = self.redis.clone().get_proxy_accs().await?;
+        *self.accs.write().unwrap() = accs.into_iter().map(|acc| (acc.id.clone(), acc)).collect();
+        Ok(())
+    }
+
+    pub async fn apply(&self, change: ProxyAccChanged) -> Result<()> {
+        let needs_reload = apply_change(&mut self.accs.write().unwrap(), change);
+        if needs_reload {
+            self.reload().await?;
+        }
+        Ok(())
+    }
+
+    /// load proxy accs and apply every change of proxy acc channel
+    /// returns an error when the subscription ends, callers are expected to call it again
+    pub async fn watch(self: Arc<Self>) -> Result<()> {
+        // subscribe first so no change between load and subscription is missed
+        let changes = self
+            .redis
+            .clone()
+            .subscribe_raw(DPNRedisKey::get_proxy_acc_chan())
+            .await
+            .map_err(|e| anyhow!("proxy acc cache: subscribe failed err={}", e))?;
+        let mut changes = Box::pin(changes);
+        self.reload().await?;
+        while let Some(bz) = changes.next().await {
+            let bz = bz.map_err(|e| anyhow!("proxy acc cache: subscription failed err={}", e))?;
+            match serde_json::from_slice::<ProxyAccChanged>(&bz) {
+                Ok(change) => self.apply(change).await?,
+                Err(e) => warn!("proxy acc cache: invalid change err={}", e),
+            }
+        }
+        Err(anyhow!("proxy acc cache: subscription ended"))
+    }
+}
+
+/// apply `change` to `accs`, returns true when all accs must be reloaded
+fn apply_change(accs: &mut HashMap<String, ProxyAccData>, change: ProxyAccChanged) -> bool {
+    match change {
+        ProxyAccChanged::Created(acc) | ProxyAccChanged::Updated(acc) => {
+            accs.insert(acc.id.clone(), acc);
+            false
+        }
+        ProxyAccChanged::Deleted(id) => {
+            accs.remove(&id);
+            false
+        }
+        ProxyAccChanged::RefreshAll() => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_change() {
+        let acc = |password: &str| {
+            let mut acc = ProxyAccData::new(
+                password.to_owned(),
+                0,
+                None,
+                "0xuser".to_owned(),
+                0,
+                None,
+                1,
+                1,
+                None,
+                None,
+                0,
+            );
+            acc.id = "acc".to_owned();
+            acc
+        };
+        let mut accs = HashMap::new();
+        assert!(!apply_change(
+            &mut accs,
+            ProxyAccChanged::Created(acc("old"))
+        ));
+        assert!(!apply_change(
+            &mut accs,
+            ProxyAccChanged::Updated(acc("new"))
+        ));
+        assert_eq!(accs["acc"].password, "new");
+        assert!(!apply_change(
+            &mut accs,
+            ProxyAccChanged::Deleted("acc".to_owned())
+        ));
+        assert!(accs.is_empty());
+        assert!(apply_change(&mut accs, ProxyAccChanged::RefreshAll()));
+    }
+}
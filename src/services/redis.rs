@@ -1,15 +1,42 @@
 use anyhow::{anyhow, Error, Result};
-use log::{error, info};
-use redis::{Commands as _, Connection, RedisResult};
+use futures_util::{Stream, StreamExt as _};
+use log::{error, info, warn};
+use r2d2::{CustomizeConnection, Pool, PooledConnection};
+use redis::{
+    aio::MultiplexedConnection, AsyncCommands as _, Commands as _, Connection, RedisError,
+    RedisResult,
+};
 use redis_async::client::{ConnectionBuilder, PubsubConnection};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use std::{collections::HashMap, fmt::Debug, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Debug,
+    sync::Arc,
+    time::Duration,
+};
 use url::Url;
 
-use crate::types::{bandwidth::UserBandwidthPrice, connection::ProxyAccData};
+use crate::types::{
+    bandwidth::{latest_prices, EphemeralSession, SessionTerminationReason, UserBandwidthPrice},
+    connection::{PeernodeInfo, ProxyAccData},
+    geo::Geo,
+    msg_queue::{
+        coalesce_balance_updates, ClientBalanceUpdate, DPNEvent, SessionCreatedExtra,
+        SessionTerminatedExtra,
+    },
+    stats::StatsSnapshot,
+    tier::{TierThresholds, UserTier},
+    user_xp::compute_uptime_xp,
+};
+use crate::utils::{bytes_to_hex_string, gunzip, gzip_above, inflate, log_id, normalize_address};
 
-use super::types::{PeerChanged, PeerChangedInfo, ProxyAccChanged};
+use super::geo::{verify_peer_country, GeoResolver};
+use super::types::{
+    PeerChanged, PeerChangedInfo, PeerScore, PriceBatchUpdated, ProxyAccChanged, ReconcileReport,
+    TimestampScore, UserQuestSummary,
+};
 
 struct RedisUri {
     is_tls: bool,
@@ -18,27 +45,312 @@ struct RedisUri {
     port: u16,
 }
 
+/// default ttl of active session indexes, refreshed whenever a session of the client/peer is added
+/// so only indexes of crashed masternodes age out
+pub const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(7 * 24 * 3600);
+/// suggested ttl of a cached client balance for the `*_with_ttl` balance updates
+/// balances share one hash so it is set per field with HEXPIRE, which requires redis >= 7.4,
+/// the plain updates never expire a balance so they work on older servers
+pub const DEFAULT_BALANCE_TTL: Duration = Duration::from_secs(30 * 24 * 3600);
+/// default `RedisConfig::pool_size`
+pub const DEFAULT_POOL_SIZE: u32 = 16;
+/// max difference between session handshaked_at and now accepted by `create_session`
+pub const DEFAULT_MAX_CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+
+/// first byte of a payload compressed by `publish`, the gzip follows it
+/// neither json nor protobuf (field number 0 is invalid) payloads start with it
+pub const PUBSUB_GZIP_HEADER: u8 = 0x01;
+
+/// payload of `publish`, `bz` prefixed with `PUBSUB_GZIP_HEADER` and gzipped when it is larger
+/// than `threshold` bytes, otherwise `bz` as is, None never compresses
+pub fn encode_pubsub_payload(bz: Vec<u8>, threshold: Option<usize>) -> Result<Vec<u8>> {
+    match threshold {
+        Some(threshold) if bz.len() > threshold => {
+            let gzipped = gzip_above(bz, Some(threshold))?;
+            let mut payload = Vec::with_capacity(gzipped.len() + 1);
+            payload.push(PUBSUB_GZIP_HEADER);
+            payload.extend(gzipped);
+            Ok(payload)
+        }
+        _ => Ok(bz),
+    }
+}
+
+/// inverse of `encode_pubsub_payload`, payloads without `PUBSUB_GZIP_HEADER` are returned as is
+/// `subscribe_raw` and `psubscribe` apply it, subscribers of `get_pubsub_conn` call it themselves
+pub fn decode_pubsub_payload(bz: &[u8]) -> Result<Cow<'_, [u8]>> {
+    match bz.split_first() {
+        Some((&PUBSUB_GZIP_HEADER, gzipped)) => inflate(gzipped)
+            .map(Cow::Owned)
+            .map_err(|e| anyhow!("inflate pubsub payload failed err={}", e)),
+        _ => Ok(Cow::Borrowed(bz)),
+    }
+}
+
+/// `ttl` in milliseconds for PX/PEXPIRE, which redis rejects below 1ms
+fn ttl_millis(ttl: Duration) -> Result<u64> {
+    match ttl.as_millis() {
+        0 => Err(anyhow!("invalid ttl {:?}, must be at least 1ms", ttl)),
+        ms => Ok(ms.min(u64::MAX as u128) as u64),
+    }
+}
+
+/// key exists with another redis type than the helper expects, e.g. a string left by a migration
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyTypeMismatch {
+    pub key: String,
+    pub expected: &'static str,
+    pub found: String,
+}
+
+impl std::fmt::Display for KeyTypeMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "redis key type mismatch key={} expected={} found={}",
+            self.key, self.expected, self.found
+        )
+    }
+}
+
+impl std::error::Error for KeyTypeMismatch {}
+
+#[derive(Debug, Clone)]
+pub struct RedisConfig {
+    pub uri: String,
+    /// timeout of opening each connection
+    pub connect_timeout: Duration,
+    /// read/write timeout of commands, None waits forever
+    pub response_timeout: Option<Duration>,
+    /// extra attempts of the initial connection check in `from_config`
+    pub connect_retries: u32,
+    pub retry_backoff: Duration,
+    /// max connections of the pool used by the generic helpers (`hset`, `hget`, `zadd`...)
+    pub pool_size: u32,
+    /// `publish` gzips payloads larger than this many bytes, None never compresses
+    /// compressed payloads start with `PUBSUB_GZIP_HEADER`, `subscribe_raw` and `psubscribe`
+    /// inflate them
+    pub pubsub_compress_threshold: Option<usize>,
+    /// encoding of stored values, e.g. sessions, peers and prices
+    pub serde_format: SerdeFormat,
+    /// prepended to every key, e.g. `staging:` to share a redis between deployments
+    /// pubsub channels are not prefixed
+    pub key_prefix: String,
+}
+
+impl Default for RedisConfig {
+    fn default() -> Self {
+        Self {
+            uri: "redis://127.0.0.1:6379".to_owned(),
+            connect_timeout: Duration::from_secs(5),
+            response_timeout: None,
+            connect_retries: 0,
+            retry_backoff: Duration::from_secs(1),
+            pool_size: DEFAULT_POOL_SIZE,
+            pubsub_compress_threshold: None,
+            serde_format: SerdeFormat::default(),
+            key_prefix: String::new(),
+        }
+    }
+}
+
+/// encoding of values stored by `RedisService`, values of every format are read back
+/// so the format can be switched without migrating stored values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SerdeFormat {
+    #[default]
+    Json,
+    /// json gzipped when larger than `threshold` bytes, small values stay plain json
+    GzipJson { threshold: usize },
+}
+
+/// `obj` encoded in `format`
+pub fn encode_value<T: Serialize>(obj: &T, format: SerdeFormat) -> Result<Vec<u8>> {
+    let bz = serde_json::to_vec(obj).map_err(|e| anyhow!("redis failed to encode err={}", e))?;
+    match format {
+        SerdeFormat::Json => Ok(bz),
+        SerdeFormat::GzipJson { threshold } => gzip_above(bz, Some(threshold)),
+    }
+}
+
+/// value of `encode_value` in any format
+pub fn decode_value<T: DeserializeOwned>(bz: &[u8]) -> Result<T> {
+    serde_json::from_slice(&gunzip(bz)?).map_err(|e| anyhow!("redis failed to decode err={}", e))
+}
+
+/// applies the response timeout of `RedisConfig` to pooled connections
+#[derive(Debug)]
+struct ConnTimeouts(Option<Duration>);
+
+impl CustomizeConnection<Connection, RedisError> for ConnTimeouts {
+    fn on_acquire(&self, conn: &mut Connection) -> Result<(), RedisError> {
+        conn.set_read_timeout(self.0)?;
+        conn.set_write_timeout(self.0)
+    }
+}
+
 #[derive(Debug)]
 pub struct RedisService {
     client: redis::Client,
+    pool: Pool<redis::Client>,
+    /// shared by the `*_async` helpers, cloning it reuses the same connection
+    async_conn: MultiplexedConnection,
     pubsub_con: PubsubConnection,
+    is_tls: bool,
+    config: RedisConfig,
 }
 
 impl RedisService {
     pub async fn new(redis_uri: String) -> Result<Self> {
-        let client = redis::Client::open(redis_uri.clone())
+        Self::from_config(RedisConfig {
+            uri: redis_uri,
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// same as `new` with at most `pool_size` pooled connections
+    pub async fn with_pool_size(redis_uri: String, pool_size: u32) -> Result<Self> {
+        Self::from_config(RedisConfig {
+            uri: redis_uri,
+            pool_size,
+            ..Default::default()
+        })
+        .await
+    }
+
+    pub async fn from_config(config: RedisConfig) -> Result<Self> {
+        let is_tls = Self::parse_redis_uri(&config.uri)
+            .map_err(|e| anyhow!("redis: parse uri failed err={}", e))?
+            .is_tls;
+        if !is_tls {
+            warn!("redis: connecting over plaintext, use rediss:// in production");
+        }
+
+        let client = redis::Client::open(config.uri.clone())
             .map_err(|e| anyhow!("redis: cannot open client err={}", e))?;
-        _ = client
-            .get_connection()
-            .map_err(|e| anyhow!("redis: cannot get connection err={}", e))?;
+        let mut attempt = 0;
+        while let Err(e) = client.get_connection_with_timeout(config.connect_timeout) {
+            if attempt >= config.connect_retries {
+                return Err(anyhow!("redis: cannot get connection err={}", e));
+            }
+            attempt += 1;
+            warn!(
+                "redis: cannot get connection, retrying attempt={} err={}",
+                attempt, e
+            );
+            tokio::time::sleep(config.retry_backoff).await;
+        }
 
-        let conn_builder = Self::get_redis_conn_builder_from_uri(&redis_uri)?;
+        // connections are opened on demand, the check above already reached redis
+        let pool = Pool::builder()
+            .max_size(config.pool_size)
+            .min_idle(Some(0))
+            .connection_timeout(config.connect_timeout)
+            .connection_customizer(Box::new(ConnTimeouts(config.response_timeout)))
+            // no PING on every checkout, a broken connection fails its command instead
+            .test_on_check_out(false)
+            .build(client.clone())
+            .map_err(|e| anyhow!("redis: cannot create pool err={}", e))?;
+
+        let async_conn = client
+            .get_multiplexed_tokio_connection_with_response_timeouts(
+                config.response_timeout.unwrap_or(Duration::MAX),
+                config.connect_timeout,
+            )
+            .await
+            .map_err(|e| anyhow!("redis: cannot get async connection err={}", e))?;
+
+        let conn_builder = Self::get_redis_conn_builder_from_uri(&config.uri)?;
         let pubsub_con = conn_builder
             .pubsub_connect()
             .await
             .map_err(|e| anyhow!("create pub sub connection failed err={}", e))?;
 
-        Ok(Self { client, pubsub_con })
+        Ok(Self {
+            client,
+            pool,
+            async_conn,
+            pubsub_con,
+            is_tls,
+            config,
+        })
+    }
+
+    /// connection of the pool, returned to it on drop
+    /// timeouts of `RedisConfig` are applied, see `ConnTimeouts`
+    /// keys used on it do not get `RedisConfig::key_prefix`
+    pub fn get_pooled_conn(&self) -> Result<PooledConnection<redis::Client>, r2d2::Error> {
+        self.pool.get()
+    }
+
+    /// `key` with `RedisConfig::key_prefix`, every key goes through it right before a command
+    /// so callers and `DPNRedisKey` only deal with unprefixed keys
+    fn key(&self, key: String) -> String {
+        if self.config.key_prefix.is_empty() {
+            return key;
+        }
+        format!("{}{}", self.config.key_prefix, key)
+    }
+
+    /// `key` on the key of a (key, field) pair
+    fn kf(&self, (key, field): (String, String)) -> (String, String) {
+        (self.key(key), field)
+    }
+
+    fn encode<T: Serialize>(&self, obj: &T) -> Result<Vec<u8>> {
+        encode_value(obj, self.config.serde_format)
+    }
+
+    /// same as `new` but refuses non-TLS (redis://) uri
+    pub async fn new_secure(redis_uri: String) -> Result<Self> {
+        Self::ensure_tls_uri(&redis_uri)?;
+        Self::new(redis_uri).await
+    }
+
+    fn ensure_tls_uri(redis_uri: &str) -> Result<()> {
+        let redis_info = Self::parse_redis_uri(redis_uri)
+            .map_err(|e| anyhow!("redis: parse uri failed err={}", e))?;
+        if !redis_info.is_tls {
+            return Err(anyhow!("redis: insecure uri, scheme must be 'rediss'"));
+        }
+        Ok(())
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.is_tls
+    }
+
+    /// shutdown hook, it must be called when service is stopping, after every other handle of
+    /// the service is dropped
+    /// waits until redis answered every command sent on the multiplexed connection, e.g.
+    /// publishes, then closes the multiplexed, pubsub and pooled connections of the service
+    /// clones of `get_pubsub_conn` and streams of `subscribe_raw`/`psubscribe` stay open until
+    /// their holders drop them
+    /// errors when the service is still shared, its connections then stay open
+    pub async fn flush_and_close(self: Arc<Self>) -> Result<()> {
+        let service = Arc::try_unwrap(self).map_err(|service| {
+            anyhow!(
+                "redis: cannot close, service is still shared refs={}",
+                Arc::strong_count(&service)
+            )
+        })?;
+        let RedisService {
+            mut async_conn,
+            pubsub_con,
+            pool,
+            ..
+        } = service;
+        // replies come in order, the PING reply follows the replies of every earlier command
+        redis::cmd("PING")
+            .query_async::<_, ()>(&mut async_conn)
+            .await
+            .map_err(|e| anyhow!("redis: flush failed err={}", e))?;
+        drop(async_conn);
+        drop(pubsub_con);
+        drop(pool);
+        info!("redis: connections closed");
+        Ok(())
     }
 
     fn parse_redis_uri(redis_uri: &str) -> Result<RedisUri> {
@@ -93,6 +405,56 @@ impl RedisService {
         Ok(connection_builder)
     }
 
+    /// turns WRONGTYPE replies into `KeyTypeMismatch` with the actual type of `key`
+    /// other errors are mapped by `or_else`
+    fn key_type_err(
+        conn: &mut Connection,
+        key: &str,
+        expected: &'static str,
+        e: RedisError,
+        or_else: impl FnOnce(RedisError) -> Error,
+    ) -> Error {
+        if e.code() != Some("WRONGTYPE") {
+            return or_else(e);
+        }
+        let found = redis::cmd("TYPE")
+            .arg(key)
+            .query::<String>(conn)
+            .unwrap_or_else(|_| "unknown".to_owned());
+        KeyTypeMismatch {
+            key: key.to_owned(),
+            expected,
+            found,
+        }
+        .into()
+    }
+
+    /// same as `key_type_err` over the async connection
+    async fn key_type_err_async(
+        conn: &mut MultiplexedConnection,
+        key: &str,
+        expected: &'static str,
+        e: RedisError,
+        or_else: impl FnOnce(RedisError) -> Error,
+    ) -> Error {
+        if e.code() != Some("WRONGTYPE") {
+            return or_else(e);
+        }
+        let found = redis::cmd("TYPE")
+            .arg(key)
+            .query_async::<_, String>(conn)
+            .await
+            .unwrap_or_else(|_| "unknown".to_owned());
+        KeyTypeMismatch {
+            key: key.to_owned(),
+            expected,
+            found,
+        }
+        .into()
+    }
+
+    /// shared pubsub connection, payloads are yielded byte for byte as published so compressed
+    /// ones keep `PUBSUB_GZIP_HEADER`, prefer `subscribe_raw` which inflates them
     pub fn get_pubsub_conn(self: Arc<Self>) -> PubsubConnection {
         self.pubsub_con.clone()
     }
@@ -101,17 +463,16 @@ impl RedisService {
     where
         T: Serialize,
     {
+        let key = self.key(key);
+        let value = self.encode(&obj)?;
         let mut conn = self
-            .client
-            .get_connection()
+            .get_pooled_conn()
             .map_err(|e| anyhow!("cannot get connection err={}", e))?;
-        match conn.hset::<String, String, String, usize>(
-            key,
-            field,
-            serde_json::to_string(&obj).unwrap(),
-        ) {
+        match conn.hset::<String, String, Vec<u8>, usize>(key.clone(), field, value) {
             Ok(_) => Ok(()),
-            Err(e) => Err(anyhow!("redis failed to insert err={}", e)),
+            Err(e) => Err(Self::key_type_err(&mut conn, &key, "hash", e, |e| {
+                anyhow!("redis failed to insert err={}", e)
+            })),
         }
     }
 
@@ -119,108 +480,125 @@ impl RedisService {
     where
         T: Clone + DeserializeOwned,
     {
+        let key = self.key(key);
         let mut conn = self
-            .client
-            .get_connection()
+            .get_pooled_conn()
             .map_err(|e| anyhow!("cannot get connection err={}", e))?;
-        let obj_str: String = conn
-            .hget(key.clone(), field.clone())
-            .map_err(|e| anyhow!("redis cannot get key={}:{} err={}", key, field, e))?;
-        let t = serde_json::from_str::<T>(&obj_str)
-            .map_err(|e| anyhow!("redis failed to decode err={}", e))?;
-        Ok(t)
+        let bz: Vec<u8> = conn.hget(key.clone(), field.clone()).map_err(|e| {
+            Self::key_type_err(&mut conn, &key, "hash", e, |e| {
+                anyhow!("redis cannot get key={}:{} err={}", key, field, e)
+            })
+        })?;
+        decode_value(&bz)
     }
 
     pub fn hgetall<T>(self: Arc<Self>, key: String) -> Result<Vec<(String, T)>, Error>
     where
         T: Clone + DeserializeOwned,
     {
+        let key = self.key(key);
         let mut conn = self
-            .client
-            .get_connection()
+            .get_pooled_conn()
             .map_err(|e| anyhow!("cannot get connection err={}", e))?;
-        let result: HashMap<String, String> = conn
-            .hgetall(key.clone())
-            .map_err(|e| anyhow!("redis cannot get key={} err={}", key, e))?;
+        let result: HashMap<String, Vec<u8>> = conn.hgetall(key.clone()).map_err(|e| {
+            Self::key_type_err(&mut conn, &key, "hash", e, |e| {
+                anyhow!("redis cannot get key={} err={}", key, e)
+            })
+        })?;
         let mut rs: Vec<(String, T)> = vec![];
-        for (key, obj_str) in result.iter() {
-            let proxy_acc = serde_json::from_str::<T>(&obj_str)
-                .map_err(|e| anyhow!("redis failed to decode err={}", e))?;
-            rs.push((key.clone(), proxy_acc.clone()));
+        for (key, bz) in result.iter() {
+            rs.push((key.clone(), decode_value(bz)?));
         }
         Ok(rs)
     }
 
     pub fn hdel(self: Arc<Self>, key: String, field: String) -> Result<(), Error> {
+        let key = self.key(key);
         let mut conn = self
-            .client
-            .get_connection()
+            .get_pooled_conn()
             .map_err(|e| anyhow!("cannot get connection err={}", e))?;
-        conn.hdel(key.clone(), field.clone())
-            .map_err(|e| anyhow!("redis cannot hdel key={} field={} err={}", key, field, e))?;
+        conn.hdel(key.clone(), field.clone()).map_err(|e| {
+            Self::key_type_err(&mut conn, &key, "hash", e, |e| {
+                anyhow!("redis cannot hdel key={} field={} err={}", key, field, e)
+            })
+        })?;
         Ok(())
     }
 
-    pub fn zadd(self: Arc<Self>, key: String, score: u32, value: u32) -> Result<(), Error> {
+    pub fn zadd<S: PeerScore>(
+        self: Arc<Self>,
+        key: String,
+        score: S,
+        value: u32,
+    ) -> Result<(), Error> {
+        let key = self.key(key);
         let mut conn = self
-            .client
-            .get_connection()
+            .get_pooled_conn()
             .map_err(|e| anyhow!("cannot get connection err={}", e))?;
-        match conn.zadd::<String, u32, u32, ()>(key, value, score) {
+        match conn.zadd::<String, u32, u32, ()>(key.clone(), value, score.raw()) {
             Ok(_) => Ok(()),
-            Err(e) => Err(anyhow!(
-                "redis failed to insert peer into peer queue err={}",
-                e
-            )),
+            Err(e) => Err(Self::key_type_err(&mut conn, &key, "zset", e, |e| {
+                anyhow!("redis failed to insert peer into peer queue err={}", e)
+            })),
         }
     }
 
     pub fn zrem(self: Arc<Self>, key: String, value: u32) -> Result<(), anyhow::Error> {
+        let key = self.key(key);
         let mut conn = self
-            .client
-            .get_connection()
+            .get_pooled_conn()
             .map_err(|e| anyhow!("cannot get connection err={}", e))?;
 
-        match conn.zrem::<String, u32, usize>(key, value) {
+        match conn.zrem::<String, u32, usize>(key.clone(), value) {
             Ok(_) => Ok(()),
-            Err(e) => Err(anyhow!(
-                "redis failed to remove peer in peer queue err={}",
-                e
-            )),
+            Err(e) => Err(Self::key_type_err(&mut conn, &key, "zset", e, |e| {
+                anyhow!("redis failed to remove peer in peer queue err={}", e)
+            })),
         }
     }
 
-    pub fn zsetall(self: Arc<Self>, key: String, score: u32) -> Result<(), anyhow::Error> {
+    pub fn zsetall<S: PeerScore>(
+        self: Arc<Self>,
+        key: String,
+        score: S,
+    ) -> Result<(), anyhow::Error> {
+        let key = self.key(key);
         let mut conn = self
-            .client
-            .get_connection()
+            .get_pooled_conn()
             .map_err(|e| anyhow!("cannot get connection err={}", e))?;
 
-        let elements: Vec<(u32, u32)> = conn
-            .zrange_withscores(key.clone(), 0, -1)
-            .map_err(|e| anyhow!("redis failed to get sorted set err={}", e))?;
+        let elements: Vec<(u32, u32)> =
+            conn.zrange_withscores(key.clone(), 0, -1).map_err(|e| {
+                Self::key_type_err(&mut conn, &key, "zset", e, |e| {
+                    anyhow!("redis failed to get sorted set err={}", e)
+                })
+            })?;
 
         for (value, _) in elements {
-            conn.zadd::<String, u32, u32, ()>(key.clone(), value, score)
+            conn.zadd::<String, u32, u32, ()>(key.clone(), value, score.raw())
                 .map_err(|e| anyhow!("redis failed to set scores err={}", e))?;
         }
 
         Ok(())
     }
 
-    pub fn zgetall(self: Arc<Self>, key: String) -> Result<Vec<(u32, u32)>, Error> {
+    /// (value, score) pairs, peers to be served first come first
+    pub fn zgetall<S: PeerScore>(self: Arc<Self>, key: String) -> Result<Vec<(u32, S)>, Error> {
+        let key = self.key(key);
         let mut conn = self
-            .client
-            .get_connection()
+            .get_pooled_conn()
             .map_err(|e| anyhow!("cannot get connection err={}", e))?;
 
-        let elements: Vec<(u32, u32)> = conn
-            .zrange_withscores(key.clone(), 0, -1)
-            .map_err(|e| anyhow!("redis failed to get peer queue err={}", e))?;
+        let elements: Vec<(u32, u32)> =
+            conn.zrange_withscores(key.clone(), 0, -1).map_err(|e| {
+                Self::key_type_err(&mut conn, &key, "zset", e, |e| {
+                    anyhow!("redis failed to get peer queue err={}", e)
+                })
+            })?;
 
-        let mut result: Vec<(u32, u32)> = elements
+        let mut result: Vec<(u32, S)> = elements
             .into_iter()
-            .map(|(value, score)| (value, score))
+            .map(|(value, score)| (value, S::from_raw(score)))
             .collect();
 
         result.sort_by_key(|(_value, score)| *score);
@@ -230,38 +608,458 @@ impl RedisService {
 
     /// this function is used to delete data of given key
     pub fn del(self: Arc<Self>, key: String) -> Result<(), Error> {
+        let key = self.key(key);
         let mut conn = self
-            .client
-            .get_connection()
+            .get_pooled_conn()
             .map_err(|e| anyhow!("cannot get connection err={}", e))?;
 
         conn.del(key.clone())
             .map_err(|e| anyhow!("redis failed to delete key={} err={}", key, e))
     }
 
-    pub async fn publish(self: Arc<Self>, chan_name: String, obj_str: String) -> Result<(), Error> {
+    /// async `hset`, it does not block the runtime
+    pub async fn hset_async<T>(
+        self: Arc<Self>,
+        key: String,
+        field: String,
+        obj: T,
+    ) -> Result<(), Error>
+    where
+        T: Serialize,
+    {
+        let key = self.key(key);
+        let value = self.encode(&obj)?;
+        let mut conn = self.async_conn.clone();
+        match conn
+            .hset::<String, String, Vec<u8>, usize>(key.clone(), field, value)
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Self::key_type_err_async(&mut conn, &key, "hash", e, |e| {
+                anyhow!("redis failed to insert err={}", e)
+            })
+            .await),
+        }
+    }
+
+    /// async `hget`
+    pub async fn hget_async<T>(self: Arc<Self>, key: String, field: String) -> Result<T, Error>
+    where
+        T: Clone + DeserializeOwned,
+    {
+        let key = self.key(key);
+        let mut conn = self.async_conn.clone();
+        let bz: Vec<u8> = match conn.hget(key.clone(), field.clone()).await {
+            Ok(bz) => bz,
+            Err(e) => {
+                return Err(Self::key_type_err_async(&mut conn, &key, "hash", e, |e| {
+                    anyhow!("redis cannot get key={}:{} err={}", key, field, e)
+                })
+                .await)
+            }
+        };
+        decode_value(&bz)
+    }
+
+    /// async `hgetall`
+    pub async fn hgetall_async<T>(self: Arc<Self>, key: String) -> Result<Vec<(String, T)>, Error>
+    where
+        T: Clone + DeserializeOwned,
+    {
+        let key = self.key(key);
+        let mut conn = self.async_conn.clone();
+        let result: HashMap<String, Vec<u8>> = match conn.hgetall(key.clone()).await {
+            Ok(result) => result,
+            Err(e) => {
+                return Err(Self::key_type_err_async(&mut conn, &key, "hash", e, |e| {
+                    anyhow!("redis cannot get key={} err={}", key, e)
+                })
+                .await)
+            }
+        };
+        let mut rs: Vec<(String, T)> = vec![];
+        for (key, bz) in result.iter() {
+            rs.push((key.clone(), decode_value(bz)?));
+        }
+        Ok(rs)
+    }
+
+    /// async `hdel`
+    pub async fn hdel_async(self: Arc<Self>, key: String, field: String) -> Result<(), Error> {
+        let key = self.key(key);
+        let mut conn = self.async_conn.clone();
+        if let Err(e) = conn.hdel::<_, _, ()>(key.clone(), field.clone()).await {
+            return Err(Self::key_type_err_async(&mut conn, &key, "hash", e, |e| {
+                anyhow!("redis cannot hdel key={} field={} err={}", key, field, e)
+            })
+            .await);
+        }
+        Ok(())
+    }
+
+    /// async `zadd`
+    pub async fn zadd_async<S: PeerScore>(
+        self: Arc<Self>,
+        key: String,
+        score: S,
+        value: u32,
+    ) -> Result<(), Error> {
+        let key = self.key(key);
+        let mut conn = self.async_conn.clone();
+        match conn
+            .zadd::<String, u32, u32, ()>(key.clone(), value, score.raw())
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Self::key_type_err_async(&mut conn, &key, "zset", e, |e| {
+                anyhow!("redis failed to insert peer into peer queue err={}", e)
+            })
+            .await),
+        }
+    }
+
+    /// async `zrem`
+    pub async fn zrem_async(self: Arc<Self>, key: String, value: u32) -> Result<(), Error> {
+        let key = self.key(key);
+        let mut conn = self.async_conn.clone();
+        match conn.zrem::<String, u32, usize>(key.clone(), value).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Self::key_type_err_async(&mut conn, &key, "zset", e, |e| {
+                anyhow!("redis failed to remove peer in peer queue err={}", e)
+            })
+            .await),
+        }
+    }
+
+    /// async `zgetall`
+    pub async fn zgetall_async<S: PeerScore>(
+        self: Arc<Self>,
+        key: String,
+    ) -> Result<Vec<(u32, S)>, Error> {
+        let key = self.key(key);
+        let mut conn = self.async_conn.clone();
+        let elements: Vec<(u32, u32)> = match conn.zrange_withscores(key.clone(), 0, -1).await {
+            Ok(elements) => elements,
+            Err(e) => {
+                return Err(Self::key_type_err_async(&mut conn, &key, "zset", e, |e| {
+                    anyhow!("redis failed to get peer queue err={}", e)
+                })
+                .await)
+            }
+        };
+
+        let mut result: Vec<(u32, S)> = elements
+            .into_iter()
+            .map(|(value, score)| (value, S::from_raw(score)))
+            .collect();
+
+        result.sort_by_key(|(_value, score)| *score);
+
+        Ok(result)
+    }
+
+    /// async `del`
+    pub async fn del_async(self: Arc<Self>, key: String) -> Result<(), Error> {
+        let key = self.key(key);
+        let mut conn = self.async_conn.clone();
+        conn.del(key.clone())
+            .await
+            .map_err(|e| anyhow!("redis failed to delete key={} err={}", key, e))
+    }
+
+    /// set the client balance to `new` only if the cached balance equals `expected`,
+    /// None expects no cached balance so a new client can be initialised
+    /// returns false when the balance was changed concurrently, caller should reload and retry
+    pub fn update_balance_cas(
+        self: Arc<Self>,
+        user_addr: String,
+        expected: Option<i64>,
+        new: i64,
+    ) -> Result<bool, Error> {
+        self.balance_cas(user_addr, expected, new, None)
+    }
+
+    /// same as `update_balance_cas` and (re)sets the balance ttl, e.g. `DEFAULT_BALANCE_TTL`,
+    /// `ttl` must be at least 1ms, requires redis >= 7.4
+    pub fn update_balance_cas_with_ttl(
+        self: Arc<Self>,
+        user_addr: String,
+        expected: Option<i64>,
+        new: i64,
+        ttl: Duration,
+    ) -> Result<bool, Error> {
+        self.balance_cas(user_addr, expected, new, Some(ttl))
+    }
+
+    fn balance_cas(
+        self: Arc<Self>,
+        user_addr: String,
+        expected: Option<i64>,
+        new: i64,
+        ttl: Option<Duration>,
+    ) -> Result<bool, Error> {
+        let ttl_ms = ttl.map(ttl_millis).transpose()?;
         let mut conn = self
-            .client
-            .get_connection()
+            .get_pooled_conn()
+            .map_err(|e| anyhow!("cannot get connection err={}", e))?;
+        let (k, f) = self.kf(DPNRedisKey::get_balance_kf(user_addr)?);
+
+        // compare and set in a script, a WATCH would conflict on any balance of the shared hash
+        let updated: i64 = redis::Script::new(BALANCE_CAS_SCRIPT)
+            .key(&k)
+            .arg(&f)
+            .arg(
+                expected
+                    .map(|expected| expected.to_string())
+                    .unwrap_or_default(),
+            )
+            .arg(new)
+            .arg(ttl_ms.map(|ttl_ms| ttl_ms.to_string()).unwrap_or_default())
+            .invoke(&mut conn)
+            .map_err(|e| anyhow!("redis failed to set balance key={}:{} err={}", k, f, e))?;
+        Ok(updated == 1)
+    }
+
+    /// atomically add `delta` to the client balance and return the new balance
+    pub fn incr_balance(self: Arc<Self>, user_addr: String, delta: i64) -> Result<i64, Error> {
+        let mut conn = self
+            .get_pooled_conn()
+            .map_err(|e| anyhow!("cannot get connection err={}", e))?;
+        let (k, f) = self.kf(DPNRedisKey::get_balance_kf(user_addr)?);
+        conn.hincr(&k, &f, delta)
+            .map_err(|e| anyhow!("redis failed to incr balance key={}:{} err={}", k, f, e))
+    }
+
+    /// same as `incr_balance` and (re)sets the balance ttl, e.g. `DEFAULT_BALANCE_TTL`,
+    /// `ttl` must be at least 1ms, requires redis >= 7.4
+    pub fn incr_balance_with_ttl(
+        self: Arc<Self>,
+        user_addr: String,
+        delta: i64,
+        ttl: Duration,
+    ) -> Result<i64, Error> {
+        let ttl_ms = ttl_millis(ttl)?;
+        let mut conn = self
+            .get_pooled_conn()
             .map_err(|e| anyhow!("cannot get connection err={}", e))?;
-        conn.publish(&chan_name, &obj_str)?;
+        let (k, f) = self.kf(DPNRedisKey::get_balance_kf(user_addr)?);
+        let (balance,): (i64,) = redis::pipe()
+            .atomic()
+            .hincr(&k, &f, delta)
+            .cmd("HPEXPIRE")
+            .arg(&k)
+            .arg(ttl_ms)
+            .arg("FIELDS")
+            .arg(1)
+            .arg(&f)
+            .ignore()
+            .query(&mut conn)
+            .map_err(|e| anyhow!("redis failed to incr balance key={}:{} err={}", k, f, e))?;
+        Ok(balance)
+    }
+
+    /// payload is compressed per `RedisConfig::pubsub_compress_threshold`
+    pub async fn publish(self: Arc<Self>, chan_name: String, obj_str: String) -> Result<(), Error> {
+        let mut conn = self.async_conn.clone();
+        let bz = self.pubsub_payload(obj_str)?;
+        conn.publish::<_, _, ()>(&chan_name, bz).await?;
         Ok(())
     }
 
+    /// `obj_str` compressed per `RedisConfig::pubsub_compress_threshold`, every publish goes
+    /// through it so subscribers read all channels the same way
+    fn pubsub_payload(&self, obj_str: String) -> Result<Vec<u8>> {
+        encode_pubsub_payload(obj_str.into_bytes(), self.config.pubsub_compress_threshold)
+            .map_err(|e| anyhow!("compress pubsub payload failed err={}", e))
+    }
+
+    /// publish unless `message_id` was already published on `chan_name` within `ttl`,
+    /// so a retried producer does not deliver the same message twice
+    /// returns false when the message was skipped as a duplicate, `ttl` must be at least 1ms
+    pub async fn publish_idempotent(
+        self: Arc<Self>,
+        chan_name: String,
+        message_id: String,
+        obj_str: String,
+        ttl: Duration,
+    ) -> Result<bool> {
+        let ttl_ms = ttl_millis(ttl)?;
+        let mut conn = self.async_conn.clone();
+        let k = self.key(DPNRedisKey::get_published_k(chan_name.clone(), message_id)?);
+        let rs: Option<String> = redis::cmd("SET")
+            .arg(&k)
+            .arg(1)
+            .arg("NX")
+            .arg("PX")
+            .arg(ttl_ms)
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| anyhow!("redis cannot record message key={} err={}", k, e))?;
+        if rs.is_none() {
+            return Ok(false);
+        }
+
+        let bz = match self.pubsub_payload(obj_str) {
+            Ok(bz) => bz,
+            Err(e) => {
+                _ = conn.del::<_, ()>(&k).await;
+                return Err(e);
+            }
+        };
+        if let Err(e) = conn.publish::<_, _, ()>(&chan_name, bz).await {
+            // let the retry publish it
+            _ = conn.del::<_, ()>(&k).await;
+            return Err(anyhow!(
+                "redis publish failed chan={} key={} err={}",
+                chan_name,
+                k,
+                e
+            ));
+        }
+        Ok(true)
+    }
+
+    /// record a speed test of peer unless one was accepted within `min_interval`
+    /// returns false when the test comes too soon, see `SpeedTestThrottle`
+    /// an interval below 1ms throttles nothing, every test is accepted without recording it
+    pub async fn try_accept_speed_test(
+        self: Arc<Self>,
+        ip_u32: u32,
+        now: i64,
+        min_interval: Duration,
+    ) -> Result<bool> {
+        let Ok(interval_ms) = ttl_millis(min_interval) else {
+            return Ok(true);
+        };
+        let mut conn = self.async_conn.clone();
+        let k = self.key(DPNRedisKey::get_speed_test_k(ip_u32));
+        let rs: Option<String> = redis::cmd("SET")
+            .arg(&k)
+            .arg(now)
+            .arg("NX")
+            .arg("PX")
+            .arg(interval_ms)
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| anyhow!("redis cannot record speed test key={} err={}", k, e))?;
+        Ok(rs.is_some())
+    }
+
+    /// subscribe to `channel` and yield undecoded payloads, e.g. proto or json messages
+    /// typed subscribers decode on top of it, it uses a dedicated connection like `psubscribe`
+    /// payloads of `publish` are inflated, a payload that cannot be inflated is dropped
+    /// the stream yields an error as its last item when the subscription ends, e.g. on
+    /// disconnect, so subscribers know they must resubscribe
+    pub async fn subscribe_raw(
+        self: Arc<Self>,
+        channel: String,
+    ) -> Result<impl Stream<Item = Result<Vec<u8>>>> {
+        let mut pubsub = self
+            .client
+            .get_async_pubsub()
+            .await
+            .map_err(|e| anyhow!("redis: cannot get pubsub connection err={}", e))?;
+        pubsub
+            .subscribe(&channel)
+            .await
+            .map_err(|e| anyhow!("redis: subscribe failed channel={} err={}", channel, e))?;
+        let ended = anyhow!("redis: subscription ended channel={}", channel);
+        let messages = pubsub.into_on_message().filter_map(move |msg| {
+            let payload = Self::decode_message(&channel, msg.get_payload_bytes()).map(Ok);
+            std::future::ready(payload)
+        });
+        Ok(messages.chain(futures_util::stream::once(std::future::ready(Err(ended)))))
+    }
+
+    /// subscribe to every channel matching glob `pattern`, e.g. `DPNRedisKey::get_peers_chan_pattern()`
+    /// yields (channel, payload) so subscribers can tell which channel a message came from
+    /// redis-async drops the matched channel on pmessage, so this uses a dedicated connection
+    /// payloads of `publish` are inflated, a payload that cannot be inflated is dropped
+    /// like `subscribe_raw` the stream yields an error as its last item when the subscription ends
+    pub async fn psubscribe(
+        self: Arc<Self>,
+        pattern: String,
+    ) -> Result<impl Stream<Item = Result<(String, Vec<u8>)>>> {
+        let mut pubsub = self
+            .client
+            .get_async_pubsub()
+            .await
+            .map_err(|e| anyhow!("redis: cannot get pubsub connection err={}", e))?;
+        pubsub
+            .psubscribe(&pattern)
+            .await
+            .map_err(|e| anyhow!("redis: psubscribe failed pattern={} err={}", pattern, e))?;
+        let ended = anyhow!("redis: subscription ended pattern={}", pattern);
+        let messages = pubsub.into_on_message().filter_map(|msg| {
+            let channel = msg.get_channel_name().to_owned();
+            let payload = Self::decode_message(&channel, msg.get_payload_bytes())
+                .map(|payload| Ok((channel, payload)));
+            std::future::ready(payload)
+        });
+        Ok(messages.chain(futures_util::stream::once(std::future::ready(Err(ended)))))
+    }
+
+    /// payload of a message received on `channel`, None when it cannot be inflated
+    fn decode_message(channel: &str, bz: &[u8]) -> Option<Vec<u8>> {
+        match decode_pubsub_payload(bz) {
+            Ok(payload) => Some(payload.into_owned()),
+            Err(e) => {
+                warn!("redis: invalid payload channel={} err={}", channel, e);
+                None
+            }
+        }
+    }
+
     pub async fn get_conn(self: Arc<Self>) -> RedisResult<Connection> {
         self.client.get_connection()
     }
 
+    /// try to take the lock `name` for `ttl`, e.g. for jobs that must run on a single masternode
+    /// returns None when the lock is held by another owner, `ttl` must be at least 1ms
+    /// the lock is released when the returned guard is dropped
+    pub async fn acquire_lock(
+        self: Arc<Self>,
+        name: String,
+        ttl: Duration,
+    ) -> Result<Option<LockGuard>> {
+        let ttl_ms = ttl_millis(ttl)?;
+        let mut conn = self.async_conn.clone();
+        let k = self.key(DPNRedisKey::get_lock_k(name));
+        let token = LockGuard::new_token();
+
+        let rs: Option<String> = redis::cmd("SET")
+            .arg(&k)
+            .arg(&token)
+            .arg("NX")
+            .arg("PX")
+            .arg(ttl_ms)
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| anyhow!("redis cannot acquire lock key={} err={}", k, e))?;
+
+        Ok(rs.map(|_| LockGuard {
+            redis: self.clone(),
+            key: k,
+            token,
+        }))
+    }
+
     /// remove all peers in redis cache
     /// it must be called when shutting down masternode
     pub async fn remove_all_peers(self: Arc<Self>, masternode_id: String) -> anyhow::Result<()> {
-        let (k, _) = DPNRedisKey::get_peers_kf(masternode_id.clone(), 0);
+        let (k, _) = DPNRedisKey::get_peers_kf(masternode_id.clone(), 0)?;
         let peers = self
             .clone()
-            .hgetall::<PeerChangedInfo>(k.clone())
+            .hgetall_async::<PeerChangedInfo>(k.clone())
+            .await
             .map_err(|e| anyhow!("redis get peers failed err={}", e))?;
 
         for (_, change) in peers {
+            self.clone()
+                .remove_peer_location(masternode_id.clone(), change.uuid.clone())
+                .await
+                .map_err(|e| anyhow!("redis remove peer location failed err={}", e))?;
+
             // publish peer to redis
             let change = PeerChanged::Disconnected(PeerChangedInfo {
                 uuid: change.uuid.clone(),
@@ -272,7 +1070,7 @@ impl RedisService {
             if let Err(e) = self
                 .clone()
                 .publish(
-                    DPNRedisKey::get_peers_chan(masternode_id.clone()),
+                    DPNRedisKey::get_peers_chan(masternode_id.clone())?,
                     serde_json::to_string(&change).unwrap(),
                 )
                 .await
@@ -285,11 +1083,70 @@ impl RedisService {
             }
         }
 
+        let (last_seen_k, _) = DPNRedisKey::get_peer_last_seen_kf(masternode_id.clone(), 0)?;
+        self.clone()
+            .del_async(last_seen_k)
+            .await
+            .map_err(|e| anyhow!("failed to remove peers last seen from redis err={}", e))?;
         self.clone()
-            .del(k)
+            .del_async(k)
+            .await
             .map_err(|e| anyhow!("failed to remove peers from redis err={}", e))
     }
 
+    /// peer presence heartbeat, atomically sets peer last_seen to `now` and moves its
+    /// queue score forward to `now` (ZADD XX GT), a peer not in the queue is not re-added
+    /// requires redis >= 6.2
+    pub async fn heartbeat_peer(
+        self: Arc<Self>,
+        masternode_id: String,
+        ip_u32: u32,
+        now: i64,
+    ) -> Result<()> {
+        let mut conn = self.async_conn.clone();
+        let (k, f) = self.kf(DPNRedisKey::get_peer_last_seen_kf(
+            masternode_id.clone(),
+            ip_u32,
+        )?);
+        let queue_k = self.key(DPNRedisKey::get_peer_queue_k(masternode_id)?);
+        let score = TimestampScore::from_timestamp(now.clamp(0, u32::MAX as i64) as u32);
+        redis::pipe()
+            .atomic()
+            .hset(&k, &f, now)
+            .ignore()
+            .cmd("ZADD")
+            .arg(&queue_k)
+            .arg("XX")
+            .arg("GT")
+            .arg(score.raw())
+            .arg(ip_u32)
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await
+            .map_err(|e| {
+                anyhow!(
+                    "redis peer heartbeat failed key={}:{} queue={} err={}",
+                    k,
+                    f,
+                    queue_k,
+                    e
+                )
+            })
+    }
+
+    /// unix timestamp of the last heartbeat of peer
+    pub async fn get_peer_last_seen(
+        self: Arc<Self>,
+        masternode_id: String,
+        ip_u32: u32,
+    ) -> Result<Option<i64>> {
+        let mut conn = self.async_conn.clone();
+        let (k, f) = self.kf(DPNRedisKey::get_peer_last_seen_kf(masternode_id, ip_u32)?);
+        conn.hget(&k, &f)
+            .await
+            .map_err(|e| anyhow!("redis cannot get key={}:{} err={}", k, f, e))
+    }
+
     pub async fn publish_peer(
         self: Arc<Self>,
         masternode_id: String,
@@ -298,24 +1155,36 @@ impl RedisService {
         match status.clone() {
             PeerChanged::Connected(info) => {
                 // add peer to redis hash
-                let (k, f) = DPNRedisKey::get_peers_kf(masternode_id.clone(), info.ip_u32);
-                if let Err(e) = self.clone().hset(k, f, info.clone()) {
+                let (k, f) = DPNRedisKey::get_peers_kf(masternode_id.clone(), info.ip_u32)?;
+                if let Err(e) = self.clone().hset_async(k, f, info.clone()).await {
                     return Err(anyhow!("redis peer add failed err={}", e));
                 }
+                // a migrated peer simply points to its new masternode
+                let (k, f) = DPNRedisKey::get_peer_location_kf(info.uuid.clone())?;
+                if let Err(e) = self.clone().hset_async(k, f, masternode_id.clone()).await {
+                    return Err(anyhow!("redis peer location add failed err={}", e));
+                }
             }
             PeerChanged::Disconnected(info) => {
                 // remove peer from redis hash
-                let (k, f) = DPNRedisKey::get_peers_kf(masternode_id.clone(), info.ip_u32);
-                if let Err(e) = self.clone().hdel(k, f) {
+                let (k, f) = DPNRedisKey::get_peers_kf(masternode_id.clone(), info.ip_u32)?;
+                if let Err(e) = self.clone().hdel_async(k, f).await {
                     return Err(anyhow!("redis peer removal failed err={}", e));
                 }
+                if let Err(e) = self
+                    .clone()
+                    .remove_peer_location(masternode_id.clone(), info.uuid.clone())
+                    .await
+                {
+                    return Err(anyhow!("redis peer location removal failed err={}", e));
+                }
             }
         };
 
         if let Err(e) = self
             .clone()
             .publish(
-                DPNRedisKey::get_peers_chan(masternode_id.clone()),
+                DPNRedisKey::get_peers_chan(masternode_id.clone())?,
                 serde_json::to_string(&status).unwrap(),
             )
             .await
@@ -330,25 +1199,177 @@ impl RedisService {
         Ok(())
     }
 
-    pub async fn get_peers(self: Arc<Self>, masternode_id: String) -> Result<Vec<PeerChangedInfo>> {
-        let (k, _) = DPNRedisKey::get_peers_kf(masternode_id, 0);
-        let peers = self
-            .clone()
-            .hgetall::<PeerChangedInfo>(k)
-            .map_err(|e| anyhow!("redis get peers failed err={}", e))?;
-        Ok(peers
-            .iter()
-            .map(|(_, peer_info)| peer_info.clone())
-            .collect())
+    /// remember the peer assigned to client, it must be called on ClientProcessed
+    pub async fn record_client_processed(
+        self: Arc<Self>,
+        client_id: String,
+        peer_id: String,
+    ) -> Result<()> {
+        let (k, f) = DPNRedisKey::get_client_assignment_kf(client_id)?;
+        self.clone()
+            .hset_async(k, f, peer_id)
+            .await
+            .map_err(|e| anyhow!("redis set client assignment failed err={}", e))
+    }
+
+    /// peer the client is currently assigned to
+    pub async fn get_client_assignment(
+        self: Arc<Self>,
+        client_id: String,
+    ) -> Result<Option<String>> {
+        let mut conn = self.async_conn.clone();
+        let (k, f) = self.kf(DPNRedisKey::get_client_assignment_kf(client_id)?);
+        let peer_id: Option<Vec<u8>> = conn
+            .hget(&k, &f)
+            .await
+            .map_err(|e| anyhow!("redis cannot get key={}:{} err={}", k, f, e))?;
+        peer_id.map(|bz| decode_value::<String>(&bz)).transpose()
+    }
+
+    /// it must be called when client disconnects
+    pub async fn clear_client_assignment(self: Arc<Self>, client_id: String) -> Result<()> {
+        let (k, f) = DPNRedisKey::get_client_assignment_kf(client_id)?;
+        self.clone()
+            .hdel_async(k, f)
+            .await
+            .map_err(|e| anyhow!("redis remove client assignment failed err={}", e))
+    }
+
+    /// masternode the peer is currently connected to
+    pub async fn get_peer_masternode(self: Arc<Self>, peer_id: String) -> Result<Option<String>> {
+        let mut conn = self.async_conn.clone();
+        let (k, f) = self.kf(DPNRedisKey::get_peer_location_kf(peer_id)?);
+        let masternode_id: Option<Vec<u8>> = conn
+            .hget(&k, &f)
+            .await
+            .map_err(|e| anyhow!("redis cannot get key={}:{} err={}", k, f, e))?;
+        masternode_id
+            .map(|bz| decode_value::<String>(&bz))
+            .transpose()
+    }
+
+    /// remove peer location only if it still points to `masternode_id`,
+    /// the peer may have already migrated to another masternode
+    async fn remove_peer_location(
+        self: Arc<Self>,
+        masternode_id: String,
+        peer_id: String,
+    ) -> Result<()> {
+        let mut conn = self.async_conn.clone();
+        let (k, f) = self.kf(DPNRedisKey::get_peer_location_kf(peer_id)?);
+        redis::Script::new(REMOVE_PEER_LOCATION_SCRIPT)
+            .key(&k)
+            .arg(&f)
+            .arg(self.encode(&masternode_id)?)
+            .invoke_async::<_, i64>(&mut conn)
+            .await
+            .map_err(|e| anyhow!("redis cannot hdel key={} field={} err={}", k, f, e))?;
+        Ok(())
+    }
+
+    /// peer and session gauges in prometheus text format, it only reads redis
+    /// masternodes are the ones in peer locations, so a masternode without peers is not reported
+    /// and one whose peers hash is gone reports 0 peers
+    pub async fn export_gauges(self: Arc<Self>) -> Result<String> {
+        let mut conn = self.async_conn.clone();
+        let location_k = self.key(DPNRedisKey::get_peer_location_k());
+        let locations: HashMap<String, Vec<u8>> = conn
+            .hgetall(&location_k)
+            .await
+            .map_err(|e| anyhow!("redis cannot get key={} err={}", location_k, e))?;
+        let mut peers_by_masternode: BTreeMap<String, usize> = BTreeMap::new();
+        for masternode_id in locations.into_values() {
+            let Ok(masternode_id) = decode_value::<String>(&masternode_id) else {
+                continue;
+            };
+            if peers_by_masternode.contains_key(&masternode_id) || masternode_id.is_empty() {
+                continue;
+            }
+            let k = self.key(DPNRedisKey::get_peers_kf(masternode_id.clone(), 0)?.0);
+            let peers: usize = conn
+                .hlen(&k)
+                .await
+                .map_err(|e| anyhow!("redis cannot get len key={} err={}", k, e))?;
+            peers_by_masternode.insert(masternode_id, peers);
+        }
+
+        let pattern = self.key(DPNRedisKey::get_client_sessions_k_pattern());
+        let keys: Vec<String> = conn
+            .scan_match::<_, String>(&pattern)
+            .await
+            .map_err(|e| anyhow!("redis cannot scan pattern={} err={}", pattern, e))?
+            .collect()
+            .await;
+        let mut active_sessions = 0u64;
+        for k in keys {
+            let sessions: u64 = conn
+                .scard(&k)
+                .await
+                .map_err(|e| anyhow!("redis cannot get len key={} err={}", k, e))?;
+            active_sessions += sessions;
+        }
+        drop(conn);
+
+        let active_bandwidth = self.total_active_bandwidth().await?;
+        Ok(render_gauges(
+            &peers_by_masternode,
+            active_sessions,
+            active_bandwidth,
+        ))
+    }
+
+    /// repair peers of masternode in redis to match `actual` connected peers
+    /// orphans are disconnected and missing peers connected, each with its PeerChanged event
+    pub async fn reconcile_peers(
+        self: Arc<Self>,
+        masternode_id: String,
+        actual: Vec<PeerChangedInfo>,
+    ) -> Result<ReconcileReport> {
+        let current = self.clone().get_peers(masternode_id.clone()).await?;
+        let (missing, orphans) = diff_peers(&current, &actual);
+        let report = ReconcileReport {
+            added: missing.len(),
+            removed: orphans.len(),
+        };
+
+        // orphans first, a stale entry may occupy the ip field of a missing peer
+        for info in orphans {
+            self.clone()
+                .publish_peer(masternode_id.clone(), PeerChanged::Disconnected(info))
+                .await
+                .map_err(|e| anyhow!("redis reconcile peers failed err={}", e))?;
+        }
+        for info in missing {
+            self.clone()
+                .publish_peer(masternode_id.clone(), PeerChanged::Connected(info))
+                .await
+                .map_err(|e| anyhow!("redis reconcile peers failed err={}", e))?;
+        }
+        Ok(report)
+    }
+
+    pub async fn get_peers(self: Arc<Self>, masternode_id: String) -> Result<Vec<PeerChangedInfo>> {
+        let (k, _) = DPNRedisKey::get_peers_kf(masternode_id, 0)?;
+        let peers = self
+            .clone()
+            .hgetall_async::<PeerChangedInfo>(k)
+            .await
+            .map_err(|e| anyhow!("redis get peers failed err={}", e))?;
+        Ok(peers
+            .iter()
+            .map(|(_, peer_info)| peer_info.clone())
+            .collect())
     }
 
     pub async fn publish_peer_price(
         self: Arc<Self>,
-        price: UserBandwidthPrice,
+        mut price: UserBandwidthPrice,
     ) -> anyhow::Result<()> {
-        let (k, f) = DPNRedisKey::get_price_kf(price.user_addr.clone());
+        price.updated_at = chrono::Utc::now().timestamp();
+        let (k, f) = DPNRedisKey::get_price_kf(price.user_addr.clone())?;
         self.clone()
-            .hset(k, f, price.clone())
+            .hset_async(k, f, price.clone())
+            .await
             .map_err(|e| anyhow!("redis set peer price failed err={}", e))?;
 
         self.clone()
@@ -367,11 +1388,202 @@ impl RedisService {
         Ok(())
     }
 
+    /// store all prices in one pipeline and publish them as a single `PriceBatchUpdated`
+    pub async fn publish_peer_prices(
+        self: Arc<Self>,
+        mut prices: Vec<UserBandwidthPrice>,
+    ) -> Result<()> {
+        if prices.is_empty() {
+            return Ok(());
+        }
+        let mut conn = self.async_conn.clone();
+        let now = chrono::Utc::now().timestamp();
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for price in prices.iter_mut() {
+            price.updated_at = now;
+            let (k, f) = self.kf(DPNRedisKey::get_price_kf(price.user_addr.clone())?);
+            pipe.hset(k, f, self.encode(price)?).ignore();
+        }
+        pipe.query_async::<_, ()>(&mut conn).await.map_err(|e| {
+            anyhow!(
+                "redis set peer prices failed len={} err={}",
+                prices.len(),
+                e
+            )
+        })?;
+
+        let batch = PriceBatchUpdated(prices);
+        self.clone()
+            .publish(
+                DPNRedisKey::get_price_batch_chan(),
+                serde_json::to_string(&batch).unwrap(),
+            )
+            .await
+            .map_err(|e| {
+                anyhow!(
+                    "redis peer prices publish failed len={} err={}",
+                    batch.0.len(),
+                    e
+                )
+            })
+    }
+
+    /// publish only the latest update of each user, so a settlement batch that changes
+    /// a balance many times results in a single update per user
+    pub async fn publish_balance_updates(
+        self: Arc<Self>,
+        updates: Vec<ClientBalanceUpdate>,
+    ) -> Result<()> {
+        let updates = coalesce_balance_updates(updates);
+        if updates.is_empty() {
+            return Ok(());
+        }
+        let mut conn = self.async_conn.clone();
+        let chan = DPNRedisKey::get_balance_chan();
+        let mut pipe = redis::pipe();
+        for update in updates.iter() {
+            pipe.publish(
+                &chan,
+                self.pubsub_payload(serde_json::to_string(update).unwrap())?,
+            )
+            .ignore();
+        }
+        pipe.query_async::<_, ()>(&mut conn).await.map_err(|e| {
+            anyhow!(
+                "redis balance updates publish failed len={} err={}",
+                updates.len(),
+                e
+            )
+        })
+    }
+
+    /// set flag and notify `FeatureFlags` readers to reload
+    pub async fn set_feature_flag(self: Arc<Self>, name: String, enabled: bool) -> Result<()> {
+        self.clone()
+            .hset_async(DPNRedisKey::get_feature_flags_k(), name.clone(), enabled)
+            .await
+            .map_err(|e| anyhow!("redis set feature flag failed name={} err={}", name, e))?;
+        self.clone()
+            .publish(DPNRedisKey::get_feature_flags_chan(), name.clone())
+            .await
+            .map_err(|e| anyhow!("redis feature flag publish failed name={} err={}", name, e))
+    }
+
+    /// raw values of all feature flags, decoding is left to `FeatureFlags`
+    /// so a single malformed value does not hide the other flags
+    pub async fn get_feature_flags(self: Arc<Self>) -> Result<HashMap<String, String>> {
+        let mut conn = self.async_conn.clone();
+        let k = self.key(DPNRedisKey::get_feature_flags_k());
+        let flags: HashMap<String, Vec<u8>> = conn
+            .hgetall(&k)
+            .await
+            .map_err(|e| anyhow!("redis cannot get key={} err={}", k, e))?;
+        flags
+            .into_iter()
+            .map(|(name, bz)| Ok((name, String::from_utf8_lossy(&gunzip(&bz)?).into_owned())))
+            .collect()
+    }
+
+    pub async fn publish_stats_snapshot(self: Arc<Self>, snapshot: StatsSnapshot) -> Result<()> {
+        self.clone()
+            .publish(
+                DPNRedisKey::get_stats_chan(),
+                serde_json::to_string(&snapshot).unwrap(),
+            )
+            .await
+            .map_err(|e| {
+                anyhow!(
+                    "redis stats snapshot publish failed at={} err={}",
+                    snapshot.at,
+                    e
+                )
+            })?;
+        Ok(())
+    }
+
+    /// recompute tier of user from `points` and publish `TierChanged` on the events channel
+    /// of its routing key when a threshold was crossed, returns the published event
+    pub async fn publish_tier_changed(
+        self: Arc<Self>,
+        user_tier: &mut UserTier,
+        points: i64,
+        thresholds: &TierThresholds,
+    ) -> Result<Option<DPNEvent>> {
+        let Some(event) = user_tier.recompute(points, thresholds) else {
+            return Ok(None);
+        };
+        event.validate()?;
+        self.clone()
+            .publish(
+                DPNRedisKey::get_events_chan(event.routing_key()),
+                serde_json::to_string(&event).unwrap(),
+            )
+            .await
+            .map_err(|e| {
+                anyhow!(
+                    "redis tier changed publish failed user_addr={} err={}",
+                    log_id(&user_tier.user_addr),
+                    e
+                )
+            })?;
+        Ok(Some(event))
+    }
+
+    /// it must be called when provider reconnects
+    /// if provider price is older than `max_age_secs` by its `updated_at` (or unknown) it is removed
+    /// and provider is asked to republish via price republish channel
+    /// returns true when price was stale
+    pub async fn on_peer_reconnect(
+        self: Arc<Self>,
+        provider_addr: String,
+        max_age_secs: i64,
+    ) -> Result<bool> {
+        let mut conn = self.async_conn.clone();
+        let (price_k, price_f) = self.kf(DPNRedisKey::get_price_kf(provider_addr.clone())?);
+        let price_bz: Option<Vec<u8>> = conn
+            .hget(&price_k, &price_f)
+            .await
+            .map_err(|e| anyhow!("redis cannot get key={}:{} err={}", price_k, price_f, e))?;
+        let updated_at = price_bz.and_then(|bz| match decode_value::<UserBandwidthPrice>(&bz) {
+            Ok(price) => Some(price.updated_at),
+            Err(e) => {
+                warn!("cannot decode price key={}:{} err={}", price_k, price_f, e);
+                None
+            }
+        });
+
+        let now = chrono::Utc::now().timestamp();
+        if matches!(updated_at, Some(updated_at) if now - updated_at <= max_age_secs) {
+            return Ok(false);
+        }
+
+        conn.hdel::<_, _, ()>(&price_k, &price_f)
+            .await
+            .map_err(|e| anyhow!("redis failed to remove stale price err={}", e))?;
+
+        self.clone()
+            .publish(
+                DPNRedisKey::get_price_republish_chan(),
+                provider_addr.clone(),
+            )
+            .await
+            .map_err(|e| {
+                anyhow!(
+                    "redis price republish publish failed provider_addr={} err={}",
+                    provider_addr,
+                    e
+                )
+            })?;
+        Ok(true)
+    }
+
     pub async fn get_peers_price(self: Arc<Self>) -> Result<Vec<UserBandwidthPrice>> {
-        let (k, _) = DPNRedisKey::get_price_kf("".to_string());
+        let k = DPNRedisKey::get_price_k();
         let peers = self
             .clone()
-            .hgetall::<UserBandwidthPrice>(k)
+            .hgetall_async::<UserBandwidthPrice>(k)
+            .await
             .map_err(|e| anyhow!("redis get peers price failed err={}", e))?;
         Ok(peers
             .iter()
@@ -379,109 +1591,2489 @@ impl RedisService {
             .collect())
     }
 
-    pub async fn get_proxy_accs(self: Arc<Self>) -> Result<Vec<ProxyAccData>> {
-        let (k, _) = DPNRedisKey::get_proxy_acc_kf("".to_string());
-        let proxy_accs = self
-            .clone()
-            .hgetall::<ProxyAccData>(k)
-            .map_err(|e| anyhow!("redis get proxy accs failed err={}", e))?;
-        Ok(proxy_accs.iter().map(|(_, pad)| pad.clone()).collect())
+    /// store geoname id of each user_addr in one pipeline
+    pub async fn set_user_geos(self: Arc<Self>, entries: Vec<(String, u64)>) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let mut conn = self.async_conn.clone();
+        let mut pipe = redis::pipe();
+        for (user_addr, geoname_id) in entries.iter() {
+            let (k, f) = self.kf(DPNRedisKey::get_user_addr_geo_kf(user_addr.clone())?);
+            pipe.hset(k, f, geoname_id).ignore();
+        }
+        pipe.query_async::<_, ()>(&mut conn)
+            .await
+            .map_err(|e| anyhow!("redis set user geos failed len={} err={}", entries.len(), e))
     }
 
-    /// remove all proxy accs in redis cache
-    /// it must be called when admin started
-    /// after removal, proxy accs are loaded from db and added to redis
-    pub async fn remove_all_proxy_accs(self: Arc<Self>) -> anyhow::Result<()> {
-        let (k, _) = DPNRedisKey::get_proxy_acc_kf("".to_owned());
-        self.clone()
-            .del(k)
-            .map_err(|e| anyhow!("failed to remove peers from redis err={}", e))
+    /// user_addr -> geoname id of all users
+    pub async fn get_all_user_geos(self: Arc<Self>) -> Result<HashMap<String, u64>> {
+        let mut conn = self.async_conn.clone();
+        let k = self.key(DPNRedisKey::get_user_addr_geo_k());
+        match conn.hgetall(&k).await {
+            Ok(geos) => Ok(geos),
+            Err(e) => Err(Self::key_type_err_async(&mut conn, &k, "hash", e, |e| {
+                anyhow!("redis cannot get key={} err={}", k, e)
+            })
+            .await),
+        }
     }
 
-    pub async fn publish_proxy_acc(
-        self: Arc<Self>,
-        proxy_acc_changed: ProxyAccChanged,
-    ) -> anyhow::Result<()> {
-        match proxy_acc_changed.clone() {
-            ProxyAccChanged::Created(pad) => {
-                let (k, f) = DPNRedisKey::get_proxy_acc_kf(pad.id.clone());
-                self.clone()
-                    .hset(k, f, pad.clone())
-                    .map_err(|e| anyhow!("{}", e))?;
+    /// quests completed by user, each quest type is a hash with a field per user
+    pub async fn get_user_quests(self: Arc<Self>, user_addr: String) -> Result<UserQuestSummary> {
+        let mut conn = self.async_conn.clone();
+        let quests = [
+            DPNRedisKey::get_first_time_provider_kf(user_addr.clone())?,
+            DPNRedisKey::get_completed_8_hours_kf(user_addr.clone())?,
+            DPNRedisKey::get_completed_time_per_day_kf(user_addr.clone())?,
+            DPNRedisKey::get_invite_friend_kf(user_addr.clone())?,
+        ]
+        .map(|kf| self.kf(kf));
+        let mut pipe = redis::pipe();
+        for (k, f) in quests.iter() {
+            pipe.hexists(k, f);
+        }
+        let (first_time, completed_8h, completed_per_day, invited_friend): (
+            bool,
+            bool,
+            bool,
+            bool,
+        ) = pipe.query_async(&mut conn).await.map_err(|e| {
+            anyhow!(
+                "redis cannot get user quests user_addr={} err={}",
+                user_addr,
+                e
+            )
+        })?;
+        Ok(UserQuestSummary {
+            first_time,
+            completed_8h,
+            completed_per_day,
+            invited_friend,
+        })
+    }
+
+    /// latest price of each provider keyed by user_addr, see `latest_prices`
+    pub async fn get_latest_prices(self: Arc<Self>) -> Result<HashMap<String, UserBandwidthPrice>> {
+        let prices = self.get_peers_price().await?;
+        Ok(latest_prices(prices))
+    }
+
+    /// merge prices stored under differently cased addresses of the same provider into one
+    /// entry under the checksummed address, keeping the most recent price
+    /// `UserBandwidthPrice::new` checksums new prices, run it to migrate prices stored before
+    /// returns the number of prices removed from non normalized addresses
+    pub async fn dedupe_peer_prices(self: Arc<Self>) -> Result<usize> {
+        let mut conn = self.async_conn.clone();
+        let price_k = self.key(DPNRedisKey::get_price_k());
+        // the plan only applies to the prices it was made from, a price published meanwhile
+        // must not be overwritten by it
+        loop {
+            let stored: HashMap<String, Vec<u8>> = conn
+                .hgetall(&price_k)
+                .await
+                .map_err(|e| anyhow!("redis cannot get key={} err={}", price_k, e))?;
+            let prices = stored
+                .iter()
+                .map(|(field, bz)| Ok((field.clone(), decode_value::<UserBandwidthPrice>(bz)?)))
+                .collect::<Result<Vec<_>>>()?;
+
+            let (latest, removed) = plan_price_dedupe(prices);
+            if removed.is_empty() {
+                return Ok(0);
             }
-            ProxyAccChanged::Updated(pad) => {
-                let (k, f) = DPNRedisKey::get_proxy_acc_kf(pad.id.clone());
-                self.clone()
-                    .hset(k, f, pad.clone())
-                    .map_err(|e| anyhow!("{}", e))?;
+            let script = redis::Script::new(DEDUPE_PRICES_SCRIPT);
+            let mut invocation = script.key(&price_k);
+            invocation.arg(stored.len());
+            for (field, bz) in stored.iter() {
+                invocation.arg(field).arg(bz);
             }
-            ProxyAccChanged::Deleted(id) => {
-                let (k, f) = DPNRedisKey::get_proxy_acc_kf(id.clone());
-                self.clone().hdel(k, f).map_err(|e| anyhow!("{}", e))?;
+            invocation.arg(latest.len());
+            for price in latest.iter() {
+                invocation.arg(&price.user_addr).arg(self.encode(price)?);
+            }
+            for field in removed.iter() {
+                invocation.arg(field);
+            }
+            let applied: i64 = invocation
+                .invoke_async(&mut conn)
+                .await
+                .map_err(|e| anyhow!("redis dedupe peer prices failed err={}", e))?;
+            if applied == 1 {
+                return Ok(removed.len());
             }
-            ProxyAccChanged::RefreshAll() => { /**/ }
         }
+    }
 
-        if let Err(e) = self
-            .clone()
-            .publish(
-                DPNRedisKey::get_proxy_acc_chan(),
-                serde_json::to_string(&proxy_acc_changed).unwrap(),
-            )
+    /// credit peer with xp for `connected_secs` of uptime and return its new total
+    pub async fn accrue_uptime_xp(
+        self: Arc<Self>,
+        peer_id: String,
+        connected_secs: u64,
+        rate_per_hour: i64,
+    ) -> Result<i64> {
+        let mut conn = self.async_conn.clone();
+        let (k, f) = self.kf(DPNRedisKey::get_uptime_xp_kf(peer_id));
+        let xp = compute_uptime_xp(connected_secs, rate_per_hour);
+        conn.hincr(k.clone(), f.clone(), xp)
             .await
-        {
-            return Err(anyhow!(
-                "redis proxy acc publish failed change={:?} err={}",
-                proxy_acc_changed,
+            .map_err(|e| anyhow!("redis failed to incr uptime xp key={}:{} err={}", k, f, e))
+    }
+
+    /// index session as active by client and by peer, it must be called when session is created
+    pub async fn add_active_session(self: Arc<Self>, session: EphemeralSession) -> Result<()> {
+        self.add_active_session_with_ttl(session, DEFAULT_SESSION_TTL)
+            .await
+    }
+
+    /// same as `add_active_session` and (re)sets ttl of the client and peer indexes,
+    /// `ttl` must be at least 1ms
+    pub async fn add_active_session_with_ttl(
+        self: Arc<Self>,
+        session: EphemeralSession,
+        ttl: Duration,
+    ) -> Result<()> {
+        let ttl_ms = ttl_millis(ttl)?;
+        let mut conn = self.async_conn.clone();
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        self.pipe_add_active_session(&mut pipe, &session, ttl_ms)?;
+        pipe.query_async::<_, ()>(&mut conn).await.map_err(|e| {
+            anyhow!(
+                "redis failed to add session hash={} err={}",
+                session.hash,
                 e
-            ));
-        }
+            )
+        })
+    }
 
-        Ok(())
+    /// remove session from active indexes, it must be called when session is terminated
+    pub async fn remove_active_session(self: Arc<Self>, session: EphemeralSession) -> Result<()> {
+        let mut conn = self.async_conn.clone();
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        self.pipe_remove_active_session(&mut pipe, &session)?;
+        pipe.query_async::<_, ()>(&mut conn).await.map_err(|e| {
+            anyhow!(
+                "redis failed to remove session hash={} err={}",
+                session.hash,
+                e
+            )
+        })
     }
-}
 
-pub struct DPNRedisKey {}
-impl DPNRedisKey {
-    pub fn get_geo_kf(masternode_id: String, login_session_id: String) -> (String, String) {
-        (
-            "peer_geo".to_owned(),
-            format!("{}_{}", masternode_id.clone(), login_session_id.clone()),
-        )
+    /// store session, index it as active and publish `SessionCreated` in one transaction,
+    /// so a crash cannot leave a stored session without its event or the other way around
+    /// its bandwidth is counted in the active bandwidth, the client session count is not
+    /// changed, callers capping clients gate it with `try_open_session` beforehand
+    /// sessions with a skewed handshaked_at (see `DEFAULT_MAX_CLOCK_SKEW`) are rejected
+    pub async fn create_session(
+        self: Arc<Self>,
+        masternode_id: String,
+        session: EphemeralSession,
+    ) -> Result<()> {
+        self.create_session_with_ttl(masternode_id, session, DEFAULT_SESSION_TTL)
+            .await
     }
 
-    pub fn get_balance_kf(user_addr: String) -> (String, String) {
-        (
-            "client_user_balance".to_owned(),
-            format!("{}", user_addr),
+    /// same as `create_session`, the stored session and the active indexes expire after `ttl`
+    /// so sessions of a crashed masternode that are never terminated age out
+    pub async fn create_session_with_ttl(
+        self: Arc<Self>,
+        masternode_id: String,
+        session: EphemeralSession,
+        ttl: Duration,
+    ) -> Result<()> {
+        let ttl_ms = ttl_millis(ttl)?;
+        let mut conn = self.async_conn.clone();
+        let k = self.key(DPNRedisKey::get_session_k(session.hash.clone())?);
+        let session_bz = self.encode(&session)?;
+        let event = DPNEvent::SessionCreated(SessionCreatedExtra {
+            masternode_id,
+            session: session.clone(),
+        });
+        event.validate()?;
+        session.check_clock(
+            chrono::Utc::now().timestamp(),
+            DEFAULT_MAX_CLOCK_SKEW.as_secs() as i64,
+        )?;
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("SET")
+            .arg(&k)
+            .arg(session_bz)
+            .arg("PX")
+            .arg(ttl_ms)
+            .ignore();
+        self.pipe_add_active_session(&mut pipe, &session, ttl_ms)?;
+        self.pipe_incr_active_bandwidth(
+            &mut pipe,
+            session.bandwidth_usage.min(i64::MAX as u64) as i64,
+        );
+        pipe.publish(
+            DPNRedisKey::get_session_events_chan(),
+            self.pubsub_payload(serde_json::to_string(&event).unwrap())?,
         )
+        .ignore()
+        .query_async::<_, ()>(&mut conn)
+        .await
+        .map_err(|e| {
+            anyhow!(
+                "redis failed to create session hash={} err={}",
+                session.hash,
+                e
+            )
+        })
+    }
+
+    /// `create_session` for a geo restricted partner, the session is refused when the country
+    /// claimed by `peer` does not match the country of its ip, see `verify_peer_country`
+    pub async fn create_geo_restricted_session(
+        self: Arc<Self>,
+        masternode_id: String,
+        session: EphemeralSession,
+        peer: &PeernodeInfo,
+        resolver: &dyn GeoResolver,
+        tolerated: &[u32],
+    ) -> Result<()> {
+        verify_peer_country(resolver, peer, tolerated)?;
+        self.create_session(masternode_id, session).await
+    }
+
+    /// counterpart of `create_session`, removes session and its indexes and publishes
+    /// `SessionTerminated` in one script
+    /// the session is released from the client session count (see `try_open_session`), so a
+    /// stored session must not also be closed by `close_session`, and its stored bandwidth moves
+    /// from the active bandwidth to the country of its peer (see `on_session_terminated`),
+    /// only once for a stored session
+    /// an invalid event (see `DPNEvent::validate`) is not published, the session is still
+    /// removed and the validation error returned
+    pub async fn terminate_session(
+        self: Arc<Self>,
+        masternode_id: String,
+        session: EphemeralSession,
+        reason: SessionTerminationReason,
+    ) -> Result<()> {
+        let k = self.key(DPNRedisKey::get_session_k(session.hash.clone())?);
+        let extra = SessionTerminatedExtra {
+            masternode_id,
+            session: session.clone(),
+            reason,
+        };
+        let geoname_id = self.peer_country_async(&extra).await?;
+        let event = DPNEvent::SessionTerminated(extra);
+        let invalid = event.validate().err();
+        let payload = match invalid {
+            Some(_) => vec![],
+            None => self.pubsub_payload(serde_json::to_string(&event).unwrap())?,
+        };
+
+        let client_k = self.key(DPNRedisKey::get_client_sessions_k(
+            session.client_addr.clone(),
+        )?);
+        let peer_k = self.key(DPNRedisKey::get_peer_sessions_k(session.peer_addr.clone())?);
+        let count_k = self.key(DPNRedisKey::get_client_session_count_k(
+            session.client_addr.clone(),
+        )?);
+        let (active_k, active_f) = self.kf(DPNRedisKey::get_active_bandwidth_kf());
+        let (country_k, country_f) = self.kf(DPNRedisKey::get_country_bandwidth_kf(
+            geoname_id.unwrap_or_default(),
+        ));
+        let script = redis::Script::new(TERMINATE_SESSION_SCRIPT);
+        let mut conn = self.async_conn.clone();
+        loop {
+            let (stored_bz, stored) = self.read_session(&mut conn, &k).await?;
+            // the stored usage was counted as active, the same bytes move to the country
+            let usage = stored
+                .as_ref()
+                .map(|stored| stored.bandwidth_usage.min(i64::MAX as u64) as i64)
+                .unwrap_or_default();
+            let applied: i64 = script
+                .key(&k)
+                .key(&client_k)
+                .key(&peer_k)
+                .key(&count_k)
+                .key(&active_k)
+                .key(&country_k)
+                .arg(stored_bz.unwrap_or_default())
+                .arg(&session.hash)
+                .arg(stored.is_some() as u8)
+                .arg(-usage)
+                .arg(usage)
+                .arg(&active_f)
+                .arg(geoname_id.map(|_| country_f.as_str()).unwrap_or_default())
+                .arg(DPNRedisKey::get_session_events_chan())
+                .arg(&payload)
+                .invoke_async(&mut conn)
+                .await
+                .map_err(|e| {
+                    anyhow!(
+                        "redis failed to terminate session hash={} err={}",
+                        session.hash,
+                        e
+                    )
+                })?;
+            if applied == 1 {
+                break;
+            }
+        }
+        match invalid {
+            Some(e) => Err(e.into()),
+            None => Ok(()),
+        }
     }
 
-    pub fn get_peer_queue_k(masternode_id: String) -> String {
-        format!("peer_queue_ms#{}_", masternode_id)
+    /// set bandwidth usage of the stored session and add the change to the active bandwidth
+    /// returns false when session is not stored (terminated or expired)
+    pub async fn update_session_bandwidth(
+        self: Arc<Self>,
+        session_hash: String,
+        bandwidth_usage: u64,
+    ) -> Result<bool> {
+        let k = self.key(DPNRedisKey::get_session_k(session_hash.clone())?);
+        let (active_k, active_f) = self.kf(DPNRedisKey::get_active_bandwidth_kf());
+        let script = redis::Script::new(UPDATE_SESSION_BANDWIDTH_SCRIPT);
+        let mut conn = self.async_conn.clone();
+        loop {
+            let (stored_bz, stored) = self.read_session(&mut conn, &k).await?;
+            let (Some(stored_bz), Some(mut session)) = (stored_bz, stored) else {
+                return Ok(false);
+            };
+            let delta = bandwidth_usage.min(i64::MAX as u64) as i64
+                - session.bandwidth_usage.min(i64::MAX as u64) as i64;
+            session.bandwidth_usage = bandwidth_usage;
+            let applied: i64 = script
+                .key(&k)
+                .key(&active_k)
+                .arg(stored_bz)
+                .arg(self.encode(&session)?)
+                .arg(&active_f)
+                .arg(delta)
+                .invoke_async(&mut conn)
+                .await
+                .map_err(|e| {
+                    anyhow!(
+                        "redis failed to update session hash={} err={}",
+                        session_hash,
+                        e
+                    )
+                })?;
+            if applied == 1 {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// session stored by `create_session`, None once it is terminated or expired
+    pub async fn get_session(
+        self: Arc<Self>,
+        session_hash: String,
+    ) -> Result<Option<EphemeralSession>> {
+        let mut conn = self.async_conn.clone();
+        let k = self.key(DPNRedisKey::get_session_k(session_hash)?);
+        let session_bz: Option<Vec<u8>> = conn
+            .get(&k)
+            .await
+            .map_err(|e| anyhow!("redis cannot get key={} err={}", k, e))?;
+        session_bz
+            .map(|bz| decode_value::<EphemeralSession>(&bz))
+            .transpose()
+            .map_err(|e| anyhow!("redis failed to decode session key={} err={}", k, e))
     }
 
-    pub fn get_peers_kf(masternode_id: String, ip_u32: u32) -> (String, String) {
-        (format!("peers_ms#{}", masternode_id), format!("{}", ip_u32))
+    /// count a new session of client if it has less than `max` open sessions,
+    /// it must be called before `create_session`, the stored session is released by
+    /// `terminate_session`
+    /// returns false when client is at the cap, the counter expires after DEFAULT_SESSION_TTL without updates
+    pub async fn try_open_session(self: Arc<Self>, client_addr: String, max: u32) -> Result<bool> {
+        let mut conn = self.async_conn.clone();
+        let k = self.key(DPNRedisKey::get_client_session_count_k(client_addr)?);
+        let opened: i64 = redis::Script::new(OPEN_SESSION_SCRIPT)
+            .key(&k)
+            .arg(max)
+            .arg(DEFAULT_SESSION_TTL.as_secs())
+            .invoke_async(&mut conn)
+            .await
+            .map_err(|e| anyhow!("redis cannot open session key={} err={}", k, e))?;
+        Ok(opened == 1)
     }
 
-    pub fn get_peers_chan(masternode_id: String) -> String {
-        format!("peers_updated_ms#{}", masternode_id)
+    /// release a session counted by `try_open_session` that was never stored by `create_session`
+    pub async fn close_session(self: Arc<Self>, client_addr: String) -> Result<()> {
+        let mut conn = self.async_conn.clone();
+        let k = self.key(DPNRedisKey::get_client_session_count_k(client_addr)?);
+        redis::Script::new(CLOSE_SESSION_SCRIPT)
+            .key(&k)
+            .invoke_async::<_, i64>(&mut conn)
+            .await
+            .map_err(|e| anyhow!("redis cannot close session key={} err={}", k, e))?;
+        Ok(())
     }
 
-    pub fn get_price_kf(peer_addr: String) -> (String, String) {
-        ("peer_price".to_owned(), peer_addr)
+    /// add `delta` bytes to the running bandwidth total of active sessions and return it
+    /// a terminated session must subtract its bandwidth_usage
+    pub async fn incr_active_bandwidth(self: Arc<Self>, delta: i64) -> Result<i64> {
+        let mut conn = self.async_conn.clone();
+        let (k, f) = self.kf(DPNRedisKey::get_active_bandwidth_kf());
+        conn.hincr(k.clone(), f.clone(), delta).await.map_err(|e| {
+            anyhow!(
+                "redis failed to incr active bandwidth key={}:{} err={}",
+                k,
+                f,
+                e
+            )
+        })
     }
 
-    pub fn get_proxy_acc_kf(id: String) -> (String, String) {
-        ("proxy_acc".to_owned(), id)
+    /// bytes used by all active sessions
+    pub async fn total_active_bandwidth(self: Arc<Self>) -> Result<u64> {
+        let mut conn = self.async_conn.clone();
+        let (k, f) = self.kf(DPNRedisKey::get_active_bandwidth_kf());
+        let total: Option<i64> = conn
+            .hget(&k, &f)
+            .await
+            .map_err(|e| anyhow!("redis cannot get key={}:{} err={}", k, f, e))?;
+        Ok(total.unwrap_or_default().max(0) as u64)
     }
 
-    pub fn get_proxy_acc_chan() -> String {
-        "proxy_acc_updated".to_string()
+    /// reset running total to bandwidth of the authoritative active `sessions`, e.g. periodically
+    /// to fix drift from lost deltas, returns the new total
+    /// `observed` is the `total_active_bandwidth` read before `sessions` were listed, nothing is
+    /// reset (None) when a delta was applied since, as it may be missing from `sessions`
+    pub async fn reconcile_active_bandwidth(
+        self: Arc<Self>,
+        observed: u64,
+        sessions: &[EphemeralSession],
+    ) -> Result<Option<u64>> {
+        let mut conn = self.async_conn.clone();
+        let (k, f) = self.kf(DPNRedisKey::get_active_bandwidth_kf());
+        let total = sessions
+            .iter()
+            .fold(0u64, |total, s| total.saturating_add(s.bandwidth_usage));
+        let reset: i64 = redis::Script::new(RECONCILE_ACTIVE_BANDWIDTH_SCRIPT)
+            .key(&k)
+            .arg(&f)
+            .arg(observed.min(i64::MAX as u64))
+            .arg(total.min(i64::MAX as u64))
+            .invoke_async(&mut conn)
+            .await
+            .map_err(|e| anyhow!("redis failed to set key={}:{} err={}", k, f, e))?;
+        Ok((reset == 1).then_some(total))
     }
 
-    pub fn get_price_chan() -> String {
-        "price_updated".to_string()
+    /// add `bytes` to the bandwidth counter of country `geoname_id` and return it
+    pub async fn record_session_country_bandwidth(
+        self: Arc<Self>,
+        geoname_id: u32,
+        bytes: u64,
+    ) -> Result<u64> {
+        let mut conn = self.async_conn.clone();
+        let (k, f) = self.kf(DPNRedisKey::get_country_bandwidth_kf(geoname_id));
+        let total: i64 = conn
+            .hincr(&k, &f, bytes.min(i64::MAX as u64))
+            .await
+            .map_err(|e| {
+                anyhow!(
+                    "redis failed to incr country bandwidth key={}:{} err={}",
+                    k,
+                    f,
+                    e
+                )
+            })?;
+        Ok(total.max(0) as u64)
+    }
+
+    pub async fn get_country_bandwidth(self: Arc<Self>, geoname_id: u32) -> Result<u64> {
+        let mut conn = self.async_conn.clone();
+        let (k, f) = self.kf(DPNRedisKey::get_country_bandwidth_kf(geoname_id));
+        let total: Option<i64> = conn
+            .hget(&k, &f)
+            .await
+            .map_err(|e| anyhow!("redis cannot get key={}:{} err={}", k, f, e))?;
+        Ok(total.unwrap_or_default().max(0) as u64)
+    }
+
+    /// it must be called on `SessionTerminated` of a session not terminated through
+    /// `terminate_session` (which already does it), adds the session bandwidth to the country
+    /// of its peer, taken from the peer geo stored on connect
+    /// returns the country geoname id, None when the peer country is unknown
+    pub async fn on_session_terminated(
+        self: Arc<Self>,
+        extra: SessionTerminatedExtra,
+    ) -> Result<Option<u32>> {
+        let Some(geoname_id) = self.peer_country_async(&extra).await? else {
+            return Ok(None);
+        };
+        self.record_session_country_bandwidth(geoname_id, extra.session.bandwidth_usage)
+            .await?;
+        Ok(Some(geoname_id))
+    }
+
+    /// country geoname id of the peer of a terminated session, None when it is unknown
+    async fn peer_country_async(&self, extra: &SessionTerminatedExtra) -> Result<Option<u32>> {
+        let (k, f) = self.peer_geo_kf(extra)?;
+        let mut conn = self.async_conn.clone();
+        let geo: Option<Vec<u8>> = conn
+            .hget(&k, &f)
+            .await
+            .map_err(|e| anyhow!("redis cannot get key={}:{} err={}", k, f, e))?;
+        Ok(Self::geo_country(extra, &k, &f, geo))
+    }
+
+    fn peer_geo_kf(&self, extra: &SessionTerminatedExtra) -> Result<(String, String)> {
+        Ok(self.kf(DPNRedisKey::get_geo_kf(
+            extra.masternode_id.clone(),
+            extra.session.login_session_id.clone(),
+        )?))
+    }
+
+    fn geo_country(
+        extra: &SessionTerminatedExtra,
+        k: &str,
+        f: &str,
+        geo: Option<Vec<u8>>,
+    ) -> Option<u32> {
+        let geoname_id = geo
+            .and_then(|geo| decode_value::<Geo>(&geo).ok())
+            .and_then(|geo| geo.country)
+            .and_then(|country| country.geoname_id);
+        if geoname_id.is_none() {
+            warn!(
+                "session terminated with unknown peer country session={} key={}:{}",
+                log_id(&extra.session.hash),
+                k,
+                f
+            );
+        }
+        geoname_id
+    }
+
+    /// hashes of active sessions of client
+    pub async fn get_client_sessions(self: Arc<Self>, client_addr: String) -> Result<Vec<String>> {
+        let mut conn = self.async_conn.clone();
+        let k = self.key(DPNRedisKey::get_client_sessions_k(client_addr)?);
+        conn.smembers(k.clone())
+            .await
+            .map_err(|e| anyhow!("redis cannot get client sessions key={} err={}", k, e))
+    }
+
+    /// hashes of active sessions served by peer, e.g. to terminate them when peer disconnects
+    pub async fn get_sessions_for_peer(self: Arc<Self>, peer_addr: String) -> Result<Vec<String>> {
+        let mut conn = self.async_conn.clone();
+        let k = self.key(DPNRedisKey::get_peer_sessions_k(peer_addr)?);
+        conn.smembers(k.clone())
+            .await
+            .map_err(|e| anyhow!("redis cannot get peer sessions key={} err={}", k, e))
+    }
+
+    /// queue the commands indexing `session` as active by client and by peer,
+    /// both indexes (re)get a ttl of `ttl_ms`, see `ttl_millis`
+    fn pipe_add_active_session(
+        &self,
+        pipe: &mut redis::Pipeline,
+        session: &EphemeralSession,
+        ttl_ms: u64,
+    ) -> Result<()> {
+        let client_k = self.key(DPNRedisKey::get_client_sessions_k(
+            session.client_addr.clone(),
+        )?);
+        let peer_k = self.key(DPNRedisKey::get_peer_sessions_k(session.peer_addr.clone())?);
+        pipe.sadd(&client_k, &session.hash)
+            .ignore()
+            .sadd(&peer_k, &session.hash)
+            .ignore()
+            .pexpire(&client_k, ttl_ms as i64)
+            .ignore()
+            .pexpire(&peer_k, ttl_ms as i64)
+            .ignore();
+        Ok(())
+    }
+
+    /// queue the command adding `delta` bytes to the active bandwidth, see `incr_active_bandwidth`
+    fn pipe_incr_active_bandwidth(&self, pipe: &mut redis::Pipeline, delta: i64) {
+        let (k, f) = self.kf(DPNRedisKey::get_active_bandwidth_kf());
+        pipe.hincr(k, f, delta).ignore();
+    }
+
+    /// session stored at `k` as stored and decoded, both None when it is missing
+    /// the stored bytes are compared by the session scripts so they only apply to the session
+    /// they were built from, an undecodable session is only returned as stored
+    async fn read_session(
+        &self,
+        conn: &mut MultiplexedConnection,
+        k: &str,
+    ) -> Result<(Option<Vec<u8>>, Option<EphemeralSession>)> {
+        let session_bz: Option<Vec<u8>> = conn
+            .get(k)
+            .await
+            .map_err(|e| anyhow!("redis cannot get key={} err={}", k, e))?;
+        let stored =
+            session_bz
+                .as_ref()
+                .and_then(|bz| match decode_value::<EphemeralSession>(bz) {
+                    Ok(session) => Some(session),
+                    Err(e) => {
+                        warn!("cannot decode session key={} err={}", k, e);
+                        None
+                    }
+                });
+        Ok((session_bz, stored))
+    }
+
+    /// queue the commands removing `session` from the active indexes
+    fn pipe_remove_active_session(
+        &self,
+        pipe: &mut redis::Pipeline,
+        session: &EphemeralSession,
+    ) -> Result<()> {
+        let client_k = self.key(DPNRedisKey::get_client_sessions_k(
+            session.client_addr.clone(),
+        )?);
+        let peer_k = self.key(DPNRedisKey::get_peer_sessions_k(session.peer_addr.clone())?);
+        pipe.srem(&client_k, &session.hash)
+            .ignore()
+            .srem(&peer_k, &session.hash)
+            .ignore();
+        Ok(())
+    }
+
+    pub async fn get_proxy_accs(self: Arc<Self>) -> Result<Vec<ProxyAccData>> {
+        let (k, _) = DPNRedisKey::get_proxy_acc_kf("".to_string());
+        let proxy_accs = self
+            .clone()
+            .hgetall_async::<ProxyAccData>(k)
+            .await
+            .map_err(|e| anyhow!("redis get proxy accs failed err={}", e))?;
+        Ok(proxy_accs.iter().map(|(_, pad)| pad.clone()).collect())
+    }
+
+    /// remove all proxy accs in redis cache
+    /// it must be called when admin started
+    /// after removal, proxy accs are loaded from db and added to redis
+    pub async fn remove_all_proxy_accs(self: Arc<Self>) -> anyhow::Result<()> {
+        let (k, _) = DPNRedisKey::get_proxy_acc_kf("".to_owned());
+        self.clone()
+            .del_async(k)
+            .await
+            .map_err(|e| anyhow!("failed to remove peers from redis err={}", e))
+    }
+
+    pub async fn publish_proxy_acc(
+        self: Arc<Self>,
+        proxy_acc_changed: ProxyAccChanged,
+    ) -> anyhow::Result<()> {
+        match proxy_acc_changed.clone() {
+            ProxyAccChanged::Created(pad) => {
+                let (k, f) = DPNRedisKey::get_proxy_acc_kf(pad.id.clone());
+                self.clone()
+                    .hset_async(k, f, pad.clone())
+                    .await
+                    .map_err(|e| anyhow!("{}", e))?;
+            }
+            ProxyAccChanged::Updated(pad) => {
+                let (k, f) = DPNRedisKey::get_proxy_acc_kf(pad.id.clone());
+                self.clone()
+                    .hset_async(k, f, pad.clone())
+                    .await
+                    .map_err(|e| anyhow!("{}", e))?;
+            }
+            ProxyAccChanged::Deleted(id) => {
+                let (k, f) = DPNRedisKey::get_proxy_acc_kf(id.clone());
+                self.clone()
+                    .hdel_async(k, f)
+                    .await
+                    .map_err(|e| anyhow!("{}", e))?;
+            }
+            ProxyAccChanged::RefreshAll() => { /**/ }
+        }
+
+        if let Err(e) = self
+            .clone()
+            .publish(
+                DPNRedisKey::get_proxy_acc_chan(),
+                serde_json::to_string(&proxy_acc_changed).unwrap(),
+            )
+            .await
+        {
+            return Err(anyhow!(
+                "redis proxy acc publish failed change={:?} err={}",
+                proxy_acc_changed,
+                e
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// (missing, orphans) of `current` peers compared to `actual` ones
+fn diff_peers(
+    current: &[PeerChangedInfo],
+    actual: &[PeerChangedInfo],
+) -> (Vec<PeerChangedInfo>, Vec<PeerChangedInfo>) {
+    let missing = actual
+        .iter()
+        .filter(|info| !current.contains(info))
+        .cloned()
+        .collect();
+    let orphans = current
+        .iter()
+        .filter(|info| !actual.contains(info))
+        .cloned()
+        .collect();
+    (missing, orphans)
+}
+
+/// (latest prices to store under their normalized address, fields to remove) for providers
+/// stored under a non normalized address
+fn plan_price_dedupe(
+    stored: Vec<(String, UserBandwidthPrice)>,
+) -> (Vec<UserBandwidthPrice>, Vec<String>) {
+    let mut removed = vec![];
+    let mut changed: HashSet<String> = HashSet::new();
+    let mut prices = vec![];
+    for (field, price) in stored {
+        match normalize_address(&field) {
+            Ok(addr) if addr != field => {
+                changed.insert(addr);
+                removed.push(field);
+            }
+            _ => {}
+        }
+        prices.push(price);
+    }
+    let latest = latest_prices(prices)
+        .into_values()
+        .filter(|price| changed.contains(&price.user_addr))
+        .collect();
+    (latest, removed)
+}
+
+/// prometheus text exposition of the gauges of `export_gauges`
+fn render_gauges(
+    peers_by_masternode: &BTreeMap<String, usize>,
+    active_sessions: u64,
+    active_bandwidth: u64,
+) -> String {
+    let escape = |v: &str| {
+        v.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    };
+    let mut out = String::new();
+    out.push_str("# HELP dpn_active_peers Connected peers of masternode\n");
+    out.push_str("# TYPE dpn_active_peers gauge\n");
+    for (masternode_id, peers) in peers_by_masternode {
+        out.push_str(&format!(
+            "dpn_active_peers{{masternode=\"{}\"}} {}\n",
+            escape(masternode_id),
+            peers
+        ));
+    }
+    out.push_str("# HELP dpn_active_sessions Active sessions\n");
+    out.push_str("# TYPE dpn_active_sessions gauge\n");
+    out.push_str(&format!("dpn_active_sessions {}\n", active_sessions));
+    out.push_str("# HELP dpn_active_bandwidth_bytes Bandwidth used by active sessions\n");
+    out.push_str("# TYPE dpn_active_bandwidth_bytes gauge\n");
+    out.push_str(&format!(
+        "dpn_active_bandwidth_bytes {}\n",
+        active_bandwidth
+    ));
+    out
+}
+
+const OPEN_SESSION_SCRIPT: &str = r#"
+local count = tonumber(redis.call("GET", KEYS[1]) or "0")
+if count >= tonumber(ARGV[1]) then
+    return 0
+end
+redis.call("INCR", KEYS[1])
+redis.call("EXPIRE", KEYS[1], ARGV[2])
+return 1
+"#;
+
+const CLOSE_SESSION_SCRIPT: &str = r#"
+local count = tonumber(redis.call("GET", KEYS[1]) or "0")
+if count > 0 then
+    return redis.call("DECR", KEYS[1])
+end
+return 0
+"#;
+
+// KEYS: session, client sessions, peer sessions, client session count, active bandwidth,
+// country bandwidth
+// ARGV[1] is the session read by the caller ("" when missing), nothing is changed when it was
+// replaced meanwhile, ARGV[3] is "1" when it was counted, an empty ARGV[9] publishes nothing
+const TERMINATE_SESSION_SCRIPT: &str = r#"
+if (redis.call("GET", KEYS[1]) or "") ~= ARGV[1] then
+    return 0
+end
+redis.call("DEL", KEYS[1])
+redis.call("SREM", KEYS[2], ARGV[2])
+redis.call("SREM", KEYS[3], ARGV[2])
+if ARGV[3] == "1" then
+    local count = tonumber(redis.call("GET", KEYS[4]) or "0")
+    if count > 0 then
+        redis.call("DECR", KEYS[4])
+    end
+    redis.call("HINCRBY", KEYS[5], ARGV[6], ARGV[4])
+    if ARGV[7] ~= "" then
+        redis.call("HINCRBY", KEYS[6], ARGV[7], ARGV[5])
+    end
+end
+if ARGV[9] ~= "" then
+    redis.call("PUBLISH", ARGV[8], ARGV[9])
+end
+return 1
+"#;
+
+// KEYS: session, active bandwidth
+// ARGV[1] is the session read by the caller, nothing is changed when it was replaced meanwhile
+const UPDATE_SESSION_BANDWIDTH_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) ~= ARGV[1] then
+    return 0
+end
+redis.call("SET", KEYS[1], ARGV[2], "KEEPTTL")
+redis.call("HINCRBY", KEYS[2], ARGV[3], ARGV[4])
+return 1
+"#;
+
+// ARGV: number of prices read by the caller followed by their field and value, number of merged
+// prices followed by their field and value, then the fields to delete
+// nothing is changed when the prices are not exactly the ones read
+const DEDUPE_PRICES_SCRIPT: &str = r#"
+local n = tonumber(ARGV[1])
+if redis.call("HLEN", KEYS[1]) ~= n then
+    return 0
+end
+for i = 0, n - 1 do
+    if redis.call("HGET", KEYS[1], ARGV[2 + 2 * i]) ~= ARGV[3 + 2 * i] then
+        return 0
+    end
+end
+local at = 2 + 2 * n
+local m = tonumber(ARGV[at])
+for i = 0, m - 1 do
+    redis.call("HSET", KEYS[1], ARGV[at + 1 + 2 * i], ARGV[at + 2 + 2 * i])
+end
+for i = at + 1 + 2 * m, #ARGV do
+    redis.call("HDEL", KEYS[1], ARGV[i])
+end
+return 1
+"#;
+
+// ARGV[2] is the total observed by the caller, a negative total (drift) is observed as 0
+const RECONCILE_ACTIVE_BANDWIDTH_SCRIPT: &str = r#"
+local current = tonumber(redis.call("HGET", KEYS[1], ARGV[1]) or "0")
+if math.max(current, 0) ~= tonumber(ARGV[2]) then
+    return 0
+end
+redis.call("HSET", KEYS[1], ARGV[1], ARGV[3])
+return 1
+"#;
+
+// an empty ARGV[2] expects the balance field to be missing, ARGV[4] is the ttl in ms, empty
+// keeps the ttl
+// HPEXPIRETIME fails on redis < 7.4 before the balance is written, HPEXPIRE would fail after
+const BALANCE_CAS_SCRIPT: &str = r#"
+if ARGV[4] ~= "" then
+    redis.call("HPEXPIRETIME", KEYS[1], "FIELDS", 1, ARGV[1])
+end
+local current = redis.call("HGET", KEYS[1], ARGV[1])
+if ARGV[2] == "" then
+    if current then
+        return 0
+    end
+elseif current ~= ARGV[2] then
+    return 0
+end
+redis.call("HSET", KEYS[1], ARGV[1], ARGV[3])
+if ARGV[4] ~= "" then
+    redis.call("HPEXPIRE", KEYS[1], ARGV[4], "FIELDS", 1, ARGV[1])
+end
+return 1
+"#;
+
+const REMOVE_PEER_LOCATION_SCRIPT: &str = r#"
+if redis.call("HGET", KEYS[1], ARGV[1]) == ARGV[2] then
+    return redis.call("HDEL", KEYS[1], ARGV[1])
+end
+return 0
+"#;
+
+// only delete / extend the lock if it is still owned by the token
+const RELEASE_LOCK_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+end
+return 0
+"#;
+
+const EXTEND_LOCK_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("PEXPIRE", KEYS[1], ARGV[2])
+end
+return 0
+"#;
+
+#[derive(Debug)]
+pub struct LockGuard {
+    redis: Arc<RedisService>,
+    key: String,
+    token: String,
+}
+
+impl LockGuard {
+    /// random owner token, another owner can never guess it and release the lock
+    fn new_token() -> String {
+        bytes_to_hex_string(&rand::random::<[u8; 16]>())
+    }
+
+    /// reset lock expiry to `ttl`, at least 1ms
+    /// returns false when the lock expired and is no longer owned by this guard
+    pub async fn extend(&self, ttl: Duration) -> Result<bool> {
+        let ttl_ms = ttl_millis(ttl)?;
+        let mut conn = self.redis.async_conn.clone();
+        let extended: i64 = redis::Script::new(EXTEND_LOCK_SCRIPT)
+            .key(&self.key)
+            .arg(&self.token)
+            .arg(ttl_ms)
+            .invoke_async(&mut conn)
+            .await
+            .map_err(|e| anyhow!("redis cannot extend lock key={} err={}", self.key, e))?;
+        Ok(extended == 1)
+    }
+
+    fn release(&self) -> Result<()> {
+        let mut conn = self
+            .redis
+            .get_pooled_conn()
+            .map_err(|e| anyhow!("cannot get connection err={}", e))?;
+        redis::Script::new(RELEASE_LOCK_SCRIPT)
+            .key(&self.key)
+            .arg(&self.token)
+            .invoke::<i64>(&mut conn)
+            .map_err(|e| anyhow!("redis cannot release lock key={} err={}", self.key, e))?;
+        Ok(())
+    }
+
+    async fn release_async(redis: Arc<RedisService>, key: String, token: String) -> Result<()> {
+        let mut conn = redis.async_conn.clone();
+        redis::Script::new(RELEASE_LOCK_SCRIPT)
+            .key(&key)
+            .arg(&token)
+            .invoke_async::<_, i64>(&mut conn)
+            .await
+            .map_err(|e| anyhow!("redis cannot release lock key={} err={}", key, e))?;
+        Ok(())
+    }
+}
+
+impl Drop for LockGuard {
+    /// inside a tokio runtime the release is spawned on the async connection
+    /// so dropping the guard never blocks a runtime thread on network io
+    fn drop(&mut self) {
+        let Ok(handle) = tokio::runtime::Handle::try_current() else {
+            if let Err(e) = self.release() {
+                error!("{}", e);
+            }
+            return;
+        };
+        let release = Self::release_async(
+            self.redis.clone(),
+            std::mem::take(&mut self.key),
+            std::mem::take(&mut self.token),
+        );
+        handle.spawn(async move {
+            if let Err(e) = release.await {
+                error!("{}", e);
+            }
+        });
+    }
+}
+
+pub struct DPNRedisKey {}
+impl DPNRedisKey {
+    /// an empty id would silently target a key shared by every caller, e.g. `peers_ms#`
+    fn ensure_id(name: &str, id: &str) -> Result<()> {
+        if id.is_empty() {
+            return Err(anyhow!("redis key: {} must not be empty", name));
+        }
+        Ok(())
+    }
+
+    pub fn get_geo_kf(masternode_id: String, login_session_id: String) -> Result<(String, String)> {
+        Self::ensure_id("masternode_id", &masternode_id)?;
+        Ok((
+            "peer_geo".to_owned(),
+            format!("{}_{}", masternode_id.clone(), login_session_id.clone()),
+        ))
+    }
+
+    pub fn get_user_addr_geo_k() -> String {
+        "user_addr_geo".to_owned()
+    }
+
+    pub fn get_user_addr_geo_kf(user_addr: String) -> Result<(String, String)> {
+        Self::ensure_id("user_addr", &user_addr)?;
+        Ok((Self::get_user_addr_geo_k(), user_addr))
+    }
+
+    pub fn get_first_time_provider_kf(user_addr: String) -> Result<(String, String)> {
+        Self::ensure_id("user_addr", &user_addr)?;
+        Ok(("first_time_provider".to_owned(), user_addr))
+    }
+
+    pub fn get_completed_8_hours_kf(user_addr: String) -> Result<(String, String)> {
+        Self::ensure_id("user_addr", &user_addr)?;
+        Ok(("completed_8_hours_ot".to_owned(), user_addr))
+    }
+
+    pub fn get_completed_time_per_day_kf(user_addr: String) -> Result<(String, String)> {
+        Self::ensure_id("user_addr", &user_addr)?;
+        Ok(("completed_time_per_day".to_owned(), user_addr))
+    }
+
+    pub fn get_invite_friend_kf(user_addr: String) -> Result<(String, String)> {
+        Self::ensure_id("user_addr", &user_addr)?;
+        Ok(("invite_friend_one_time".to_owned(), user_addr))
+    }
+
+    pub fn get_balance_k() -> String {
+        "client_user_balance".to_owned()
+    }
+
+    /// `user_addr` is used as is, balances are stored under the address their updater sends
+    pub fn get_balance_kf(user_addr: String) -> Result<(String, String)> {
+        Self::ensure_id("user_addr", &user_addr)?;
+        Ok((Self::get_balance_k(), user_addr))
+    }
+
+    pub fn get_peer_queue_k(masternode_id: String) -> Result<String> {
+        Self::ensure_id("masternode_id", &masternode_id)?;
+        Ok(format!("peer_queue_ms#{}_", masternode_id))
+    }
+
+    pub fn get_peers_kf(masternode_id: String, ip_u32: u32) -> Result<(String, String)> {
+        Self::ensure_id("masternode_id", &masternode_id)?;
+        Ok((format!("peers_ms#{}", masternode_id), format!("{}", ip_u32)))
+    }
+
+    pub fn get_peer_last_seen_kf(masternode_id: String, ip_u32: u32) -> Result<(String, String)> {
+        Self::ensure_id("masternode_id", &masternode_id)?;
+        Ok((
+            format!("peer_last_seen_ms#{}", masternode_id),
+            format!("{}", ip_u32),
+        ))
+    }
+
+    pub fn get_peers_chan(masternode_id: String) -> Result<String> {
+        Self::ensure_id("masternode_id", &masternode_id)?;
+        Ok(format!("peers_updated_ms#{}", masternode_id))
+    }
+
+    /// matches `get_peers_chan` of every masternode
+    pub fn get_peers_chan_pattern() -> String {
+        "peers_updated_ms#*".to_string()
+    }
+
+    /// reverse of `get_peers_chan`, returns the masternode_id
+    pub fn parse_peers_chan(chan: &str) -> Option<String> {
+        chan.strip_prefix("peers_updated_ms#")
+            .filter(|id| !id.is_empty())
+            .map(|id| id.to_owned())
+    }
+
+    pub fn get_country_bandwidth_kf(geoname_id: u32) -> (String, String) {
+        ("country_bandwidth".to_owned(), geoname_id.to_string())
+    }
+
+    pub fn get_active_bandwidth_kf() -> (String, String) {
+        ("active_bandwidth".to_owned(), "total".to_owned())
+    }
+
+    pub fn get_client_assignment_kf(client_id: String) -> Result<(String, String)> {
+        Self::ensure_id("client_id", &client_id)?;
+        Ok(("client_assignment".to_owned(), client_id))
+    }
+
+    pub fn get_peer_location_k() -> String {
+        "peer_location".to_owned()
+    }
+
+    pub fn get_peer_location_kf(peer_id: String) -> Result<(String, String)> {
+        Self::ensure_id("peer_id", &peer_id)?;
+        Ok((Self::get_peer_location_k(), peer_id))
+    }
+
+    pub fn get_price_k() -> String {
+        "peer_price".to_owned()
+    }
+
+    /// `peer_addr` is used as is, `UserBandwidthPrice::new` checksums it
+    /// prices stored under another casing are merged by `RedisService::dedupe_peer_prices`
+    pub fn get_price_kf(peer_addr: String) -> Result<(String, String)> {
+        Self::ensure_id("peer_addr", &peer_addr)?;
+        Ok((Self::get_price_k(), peer_addr))
+    }
+
+    pub fn get_balance_chan() -> String {
+        "balance_updated".to_owned()
+    }
+
+    pub fn get_price_batch_chan() -> String {
+        "price_batch_updated".to_string()
+    }
+
+    pub fn get_price_republish_chan() -> String {
+        "price_republish_requested".to_string()
+    }
+
+    pub fn get_proxy_acc_kf(id: String) -> (String, String) {
+        ("proxy_acc".to_owned(), id)
+    }
+
+    pub fn get_proxy_acc_chan() -> String {
+        "proxy_acc_updated".to_string()
+    }
+
+    pub fn get_uptime_xp_kf(peer_id: String) -> (String, String) {
+        ("uptime_xp".to_owned(), peer_id)
+    }
+
+    /// `client_addr` is checksummed so every casing of an address targets the same key
+    pub fn get_client_sessions_k(client_addr: String) -> Result<String> {
+        Ok(format!(
+            "sessions_by_client#{}",
+            normalize_address(&client_addr)?
+        ))
+    }
+
+    pub fn get_client_sessions_k_pattern() -> String {
+        "sessions_by_client#*".to_owned()
+    }
+
+    pub fn get_client_session_count_k(client_addr: String) -> Result<String> {
+        Ok(format!(
+            "session_count#{}",
+            normalize_address(&client_addr)?
+        ))
+    }
+
+    /// `peer_addr` is checksummed so every casing of an address targets the same key
+    pub fn get_peer_sessions_k(peer_addr: String) -> Result<String> {
+        Ok(format!(
+            "sessions_by_peer#{}",
+            normalize_address(&peer_addr)?
+        ))
+    }
+
+    /// a key per session so each expires on its own, see `create_session_with_ttl`
+    pub fn get_session_k(session_hash: String) -> Result<String> {
+        Self::ensure_id("session_hash", &session_hash)?;
+        Ok(format!("session#{}", session_hash))
+    }
+
+    pub fn get_session_events_chan() -> String {
+        "session_events".to_owned()
+    }
+
+    pub fn get_speed_test_k(ip_u32: u32) -> String {
+        format!("speed_test_accepted#{}", ip_u32)
+    }
+
+    pub fn get_published_k(chan_name: String, message_id: String) -> Result<String> {
+        Self::ensure_id("message_id", &message_id)?;
+        Ok(format!("published#{}_{}", chan_name, message_id))
+    }
+
+    pub fn get_lock_k(name: String) -> String {
+        format!("lock#{}", name)
+    }
+
+    pub fn get_price_chan() -> String {
+        "price_updated".to_string()
+    }
+
+    pub fn get_feature_flags_k() -> String {
+        "feature_flags".to_string()
+    }
+
+    pub fn get_feature_flags_chan() -> String {
+        "feature_flags_updated".to_string()
+    }
+
+    /// channel of the `DPNEvent`s with `routing_key`, see `DPNEvent::routing_key`
+    pub fn get_events_chan(routing_key: &str) -> String {
+        format!("dpn_events#{}", routing_key)
+    }
+
+    pub fn get_stats_chan() -> String {
+        "stats_snapshot".to_string()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::utils::{address_to_string, hash::hash, Address};
+
+    /// redis of the ignored tests, REDIS_URI or a local redis
+    pub(crate) fn test_redis_uri() -> String {
+        std::env::var("REDIS_URI").unwrap_or("redis://127.0.0.1:6379".to_owned())
+    }
+
+    pub(crate) async fn test_redis() -> Arc<RedisService> {
+        Arc::new(RedisService::new(test_redis_uri()).await.unwrap())
+    }
+
+    /// a service whose keys get a prefix unique to `name`, for tests asserting on shared
+    /// counters such as the active bandwidth
+    async fn test_redis_prefixed(name: &str) -> Arc<RedisService> {
+        let config = RedisConfig {
+            uri: test_redis_uri(),
+            key_prefix: format!("{}:", name),
+            ..Default::default()
+        };
+        Arc::new(RedisService::from_config(config).await.unwrap())
+    }
+
+    /// a valid address unique to `name`, so tests sharing a redis do not collide
+    fn test_addr(name: &str) -> String {
+        let addr = Address::from_slice(&hash(name.as_bytes()).as_bytes()[..20]);
+        normalize_address(&address_to_string(addr)).unwrap()
+    }
+
+    #[test]
+    fn test_ensure_tls_uri() {
+        assert!(RedisService::ensure_tls_uri("redis://:password@localhost:6379").is_err());
+        assert!(RedisService::ensure_tls_uri("rediss://:password@localhost:6379").is_ok());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_hget_on_string_key_returns_key_type_mismatch() {
+        let redis = test_redis().await;
+        let mut conn = redis.clone().get_conn().await.unwrap();
+        let key = "test_key_type_mismatch".to_owned();
+        conn.set::<_, _, ()>(&key, "plain").unwrap();
+
+        let hget_err = redis
+            .clone()
+            .hget::<String>(key.clone(), "field".to_owned())
+            .unwrap_err();
+        let zgetall_err = redis
+            .clone()
+            .zgetall::<TimestampScore>(key.clone())
+            .unwrap_err();
+        conn.del::<_, ()>(&key).unwrap();
+
+        let mismatch = |expected| KeyTypeMismatch {
+            key: key.clone(),
+            expected,
+            found: "string".to_owned(),
+        };
+        assert_eq!(
+            hget_err.downcast_ref::<KeyTypeMismatch>(),
+            Some(&mismatch("hash"))
+        );
+        assert_eq!(
+            zgetall_err.downcast_ref::<KeyTypeMismatch>(),
+            Some(&mismatch("zset"))
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_get_peer_masternode() {
+        let redis = test_redis().await;
+        let info = PeerChangedInfo {
+            uuid: "test_peer_location".to_owned(),
+            login_session_id: "login_session_id".to_owned(),
+            ip_u32: 1,
+        };
+
+        redis
+            .clone()
+            .publish_peer("ms1".to_owned(), PeerChanged::Connected(info.clone()))
+            .await
+            .unwrap();
+        let masternode = redis.clone().get_peer_masternode(info.uuid.clone()).await;
+        assert_eq!(masternode.unwrap(), Some("ms1".to_owned()));
+
+        // late disconnect from previous masternode must not remove the new location
+        redis
+            .clone()
+            .publish_peer("ms2".to_owned(), PeerChanged::Connected(info.clone()))
+            .await
+            .unwrap();
+        redis
+            .clone()
+            .publish_peer("ms1".to_owned(), PeerChanged::Disconnected(info.clone()))
+            .await
+            .unwrap();
+        let masternode = redis.clone().get_peer_masternode(info.uuid.clone()).await;
+        assert_eq!(masternode.unwrap(), Some("ms2".to_owned()));
+
+        redis
+            .clone()
+            .publish_peer("ms2".to_owned(), PeerChanged::Disconnected(info.clone()))
+            .await
+            .unwrap();
+        let masternode = redis.clone().get_peer_masternode(info.uuid.clone()).await;
+        assert_eq!(masternode.unwrap(), None);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_active_session_ttl() {
+        let redis = test_redis().await;
+        let session = |name: &str| {
+            EphemeralSession::new(
+                test_addr(name),
+                test_addr(name),
+                test_addr("peer_ttl"),
+                1,
+                1,
+                "login_session_id".to_owned(),
+            )
+        };
+        let (stale, refreshed) = (session("client_stale"), session("client_refreshed"));
+        let ttl = Duration::from_secs(2);
+        for s in [&stale, &refreshed] {
+            redis
+                .clone()
+                .add_active_session_with_ttl(s.clone(), ttl)
+                .await
+                .unwrap();
+        }
+
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        redis
+            .clone()
+            .add_active_session_with_ttl(session("client_refreshed"), ttl)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+
+        let sessions = redis
+            .clone()
+            .get_client_sessions(stale.client_addr.clone())
+            .await;
+        assert!(sessions.unwrap().is_empty());
+        let sessions = redis
+            .clone()
+            .get_client_sessions(refreshed.client_addr.clone())
+            .await;
+        assert_eq!(sessions.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_active_session_sub_second_ttl() {
+        let session = EphemeralSession::new(
+            "test_active_session_sub_second_ttl".to_owned(),
+            test_addr("test_active_session_sub_second_ttl_client"),
+            test_addr("test_active_session_sub_second_ttl_peer"),
+            1,
+            1,
+            "login_session_id".to_owned(),
+        );
+        let redis = test_redis().await;
+        let client_sessions = || {
+            redis
+                .clone()
+                .get_client_sessions(session.client_addr.clone())
+        };
+        assert!(redis
+            .clone()
+            .add_active_session_with_ttl(session.clone(), Duration::from_micros(500))
+            .await
+            .is_err());
+        redis
+            .clone()
+            .add_active_session_with_ttl(session.clone(), Duration::from_millis(500))
+            .await
+            .unwrap();
+        assert!(client_sessions().await.unwrap().contains(&session.hash));
+
+        tokio::time::sleep(Duration::from_millis(800)).await;
+        assert!(client_sessions().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_try_open_session() {
+        let redis = test_redis().await;
+        let client_addr = test_addr("client_session_cap");
+        redis
+            .clone()
+            .del(DPNRedisKey::get_client_session_count_k(client_addr.clone()).unwrap())
+            .unwrap();
+
+        for _ in 0..2 {
+            assert!(redis
+                .clone()
+                .try_open_session(client_addr.clone(), 2)
+                .await
+                .unwrap());
+        }
+        assert!(!redis
+            .clone()
+            .try_open_session(client_addr.clone(), 2)
+            .await
+            .unwrap());
+
+        redis
+            .clone()
+            .close_session(client_addr.clone())
+            .await
+            .unwrap();
+        assert!(redis
+            .clone()
+            .try_open_session(client_addr.clone(), 2)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_from_config_retries_unreachable_redis() {
+        let config = RedisConfig {
+            uri: "redis://127.0.0.1:1".to_owned(),
+            connect_timeout: Duration::from_millis(100),
+            response_timeout: Some(Duration::from_millis(100)),
+            connect_retries: 2,
+            retry_backoff: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let started = std::time::Instant::now();
+        let err = RedisService::from_config(config).await.unwrap_err();
+        assert!(err.to_string().starts_with("redis: cannot get connection"));
+        assert!(started.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_from_full_config() {
+        let uri = test_redis_uri();
+        let config = RedisConfig {
+            uri,
+            connect_timeout: Duration::from_secs(2),
+            response_timeout: Some(Duration::from_secs(2)),
+            connect_retries: 1,
+            retry_backoff: Duration::from_millis(100),
+            pool_size: 4,
+            pubsub_compress_threshold: Some(1024),
+            serde_format: SerdeFormat::GzipJson { threshold: 16 },
+            key_prefix: "test_from_full_config:".to_owned(),
+        };
+        let redis = Arc::new(RedisService::from_config(config.clone()).await.unwrap());
+        assert_eq!(redis.config.pool_size, 4);
+        assert_eq!(redis.pool.max_size(), 4);
+
+        let (k, f) = ("peers".to_owned(), "info".to_owned());
+        let value = "x".repeat(64);
+        redis.clone().hset(k.clone(), f.clone(), &value).unwrap();
+        assert_eq!(
+            redis.clone().hget::<String>(k.clone(), f.clone()).unwrap(),
+            value
+        );
+
+        // stored under the prefixed key, gzipped
+        let mut conn = redis.clone().get_conn().await.unwrap();
+        let prefixed = format!("{}{}", config.key_prefix, k);
+        let stored: Vec<u8> = conn.hget(&prefixed, &f).unwrap();
+        assert!(stored.starts_with(&crate::utils::GZIP_MAGIC));
+        assert!(!conn.exists::<_, bool>(&k).unwrap());
+        redis.del(k).unwrap();
+    }
+
+    #[test]
+    fn test_encode_value() {
+        let value = "x".repeat(64);
+        let json = encode_value(&value, SerdeFormat::Json).unwrap();
+        assert_eq!(json, serde_json::to_vec(&value).unwrap());
+        let gzipped = encode_value(&value, SerdeFormat::GzipJson { threshold: 16 }).unwrap();
+        assert!(gzipped.len() < json.len());
+        // small values stay plain json
+        let small = encode_value(&1, SerdeFormat::GzipJson { threshold: 16 }).unwrap();
+        assert_eq!(small, b"1");
+
+        // values of either format are read back whatever the configured format is
+        for bz in [json, gzipped] {
+            assert_eq!(decode_value::<String>(&bz).unwrap(), value);
+        }
+        assert!(decode_value::<String>(b"not json").is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_publish_peer_prices() {
+        let redis = test_redis().await;
+        let mut batches = redis
+            .clone()
+            .subscribe_raw(DPNRedisKey::get_price_batch_chan())
+            .await
+            .unwrap();
+        let prices: Vec<UserBandwidthPrice> = (0..20)
+            .map(|i| UserBandwidthPrice {
+                user_addr: test_addr(&format!("price_batch_{}", i)),
+                rate_per_kb: i,
+                rate_per_second: i * 2,
+                updated_at: 0,
+            })
+            .collect();
+
+        redis
+            .clone()
+            .publish_peer_prices(prices.clone())
+            .await
+            .unwrap();
+
+        let bz = batches.next().await.unwrap().unwrap();
+        let batch: PriceBatchUpdated = serde_json::from_slice(&bz).unwrap();
+        assert_eq!(batch.0.len(), 20);
+        let next = tokio::time::timeout(Duration::from_millis(200), batches.next()).await;
+        assert!(next.is_err());
+
+        let stored = redis.clone().get_peers_price().await.unwrap();
+        for price in prices {
+            let found = stored
+                .iter()
+                .find(|p| p.user_addr == price.user_addr)
+                .unwrap();
+            assert_eq!(found.rate_per_second, price.rate_per_second);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_client_assignment() {
+        let redis = test_redis().await;
+        let client_id = "test_client_assignment".to_owned();
+
+        redis
+            .clone()
+            .record_client_processed(client_id.clone(), "peer1".to_owned())
+            .await
+            .unwrap();
+        let peer_id = redis.clone().get_client_assignment(client_id.clone()).await;
+        assert_eq!(peer_id.unwrap(), Some("peer1".to_owned()));
+
+        redis
+            .clone()
+            .clear_client_assignment(client_id.clone())
+            .await
+            .unwrap();
+        let peer_id = redis.clone().get_client_assignment(client_id.clone()).await;
+        assert_eq!(peer_id.unwrap(), None);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_active_bandwidth() {
+        let redis = test_redis_prefixed("test_active_bandwidth").await;
+        let before = redis.clone().total_active_bandwidth().await.unwrap();
+
+        redis.clone().incr_active_bandwidth(1024).await.unwrap();
+        assert_eq!(
+            redis.clone().incr_active_bandwidth(512).await.unwrap(),
+            before as i64 + 1536
+        );
+        assert_eq!(
+            redis.clone().total_active_bandwidth().await.unwrap(),
+            before + 1536
+        );
+        // deltas applied after `observed` was read are kept
+        assert_eq!(
+            redis
+                .clone()
+                .reconcile_active_bandwidth(before + 1024, &[])
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            redis.clone().total_active_bandwidth().await.unwrap(),
+            before + 1536
+        );
+
+        let observed = redis.clone().total_active_bandwidth().await.unwrap();
+        assert_eq!(
+            redis
+                .clone()
+                .reconcile_active_bandwidth(observed, &[])
+                .await
+                .unwrap(),
+            Some(0)
+        );
+        assert_eq!(redis.clone().total_active_bandwidth().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_update_balance_cas() {
+        let redis = test_redis().await;
+        let user_addr = "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed".to_owned();
+        let (k, f) = DPNRedisKey::get_balance_kf(user_addr.clone()).unwrap();
+        redis.clone().hdel(k.clone(), f.clone()).unwrap();
+
+        // a missing balance is initialised, then moved from the value just set
+        assert!(redis
+            .clone()
+            .update_balance_cas(user_addr.clone(), None, 100)
+            .unwrap());
+        assert!(redis
+            .clone()
+            .update_balance_cas(user_addr.clone(), Some(100), 150)
+            .unwrap());
+        assert_eq!(redis.clone().hget::<i64>(k, f).unwrap(), 150);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis >= 7.4 at REDIS_URI"]
+    async fn test_balance_sub_second_ttl() {
+        let redis = test_redis().await;
+        let user_addr = test_addr("test_balance_sub_second_ttl");
+        let (k, f) = DPNRedisKey::get_balance_kf(user_addr.clone()).unwrap();
+        let balance = || redis.clone().hget::<Option<i64>>(k.clone(), f.clone());
+        redis.clone().hdel(k.clone(), f.clone()).unwrap();
+
+        let ttl = Duration::from_millis(500);
+        assert!(redis
+            .clone()
+            .update_balance_cas_with_ttl(user_addr.clone(), None, 100, ttl)
+            .unwrap());
+        assert_eq!(balance().unwrap(), Some(100));
+        assert_eq!(
+            redis
+                .clone()
+                .incr_balance_with_ttl(user_addr.clone(), 20, ttl)
+                .unwrap(),
+            120
+        );
+        assert_eq!(balance().unwrap(), Some(120));
+
+        // rejected without touching the balance
+        assert!(redis
+            .clone()
+            .update_balance_cas_with_ttl(user_addr.clone(), Some(120), 0, Duration::ZERO)
+            .is_err());
+        assert!(redis
+            .clone()
+            .incr_balance_with_ttl(user_addr.clone(), 1, Duration::from_micros(500))
+            .is_err());
+        assert_eq!(balance().unwrap(), Some(120));
+
+        tokio::time::sleep(Duration::from_millis(800)).await;
+        assert_eq!(balance().unwrap(), None);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_update_balance_cas_conflict() {
+        let redis = test_redis().await;
+        let user_addr = "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359".to_owned();
+        let (k, f) = DPNRedisKey::get_balance_kf(user_addr.clone()).unwrap();
+        redis.clone().hset(k.clone(), f.clone(), 100).unwrap();
+
+        // another updater moved the balance to 100, the stale expectations are refused
+        assert!(!redis
+            .clone()
+            .update_balance_cas(user_addr.clone(), Some(50), 70)
+            .unwrap());
+        assert!(!redis
+            .clone()
+            .update_balance_cas(user_addr.clone(), None, 70)
+            .unwrap());
+        assert_eq!(
+            redis.clone().hget::<i64>(k.clone(), f.clone()).unwrap(),
+            100
+        );
+        redis.hdel(k, f).unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_incr_balance() {
+        let redis = test_redis().await;
+        let user_addr = "0xdbF03B407c01E7cD3CBea99509d93f8DDDC8C6FB".to_owned();
+        let (k, f) = DPNRedisKey::get_balance_kf(user_addr.clone()).unwrap();
+        redis.clone().hdel(k.clone(), f.clone()).unwrap();
+
+        assert_eq!(
+            redis.clone().incr_balance(user_addr.clone(), 30).unwrap(),
+            30
+        );
+        assert_eq!(
+            redis.clone().incr_balance(user_addr.clone(), -10).unwrap(),
+            20
+        );
+        // increments and compare and set work on the same value
+        assert!(redis
+            .clone()
+            .update_balance_cas(user_addr.clone(), Some(20), 0)
+            .unwrap());
+        redis.hdel(k, f).unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_acquire_lock() {
+        let redis = test_redis().await;
+        let name = "test_acquire_lock".to_owned();
+        redis
+            .clone()
+            .del(DPNRedisKey::get_lock_k(name.clone()))
+            .unwrap();
+
+        let guard = redis
+            .clone()
+            .acquire_lock(name.clone(), Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(guard.is_some());
+        let guard = guard.unwrap();
+        assert!(guard.extend(Duration::from_secs(5)).await.unwrap());
+        assert!(guard.extend(Duration::ZERO).await.is_err());
+        assert!(redis
+            .acquire_lock(name, Duration::from_micros(500))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_acquire_lock_held() {
+        let redis = test_redis().await;
+        let name = "test_acquire_lock_held".to_owned();
+        redis
+            .clone()
+            .del(DPNRedisKey::get_lock_k(name.clone()))
+            .unwrap();
+
+        let _guard = redis
+            .clone()
+            .acquire_lock(name.clone(), Duration::from_secs(5))
+            .await
+            .unwrap()
+            .unwrap();
+        let second = redis
+            .acquire_lock(name, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(second.is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_lock_released_on_drop() {
+        let redis = test_redis().await;
+        let name = "test_lock_released_on_drop".to_owned();
+        redis
+            .clone()
+            .del(DPNRedisKey::get_lock_k(name.clone()))
+            .unwrap();
+
+        let guard = redis
+            .clone()
+            .acquire_lock(name.clone(), Duration::from_secs(60))
+            .await
+            .unwrap();
+        drop(guard);
+
+        // the release is spawned, give it a moment instead of waiting for the ttl
+        let reacquired = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let guard = redis
+                    .clone()
+                    .acquire_lock(name.clone(), Duration::from_secs(60))
+                    .await
+                    .unwrap();
+                if guard.is_some() {
+                    return guard;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        assert!(reacquired.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_hset_hget_async() {
+        let redis = test_redis().await;
+        let k = "test_hset_hget_async".to_owned();
+        redis.clone().del_async(k.clone()).await.unwrap();
+
+        let price = UserBandwidthPrice::new(test_addr("test_hset_hget_async"), 3, 2).unwrap();
+        redis
+            .clone()
+            .hset_async(k.clone(), "f".to_owned(), price.clone())
+            .await
+            .unwrap();
+        let stored: UserBandwidthPrice = redis
+            .clone()
+            .hget_async(k.clone(), "f".to_owned())
+            .await
+            .unwrap();
+        assert_eq!(stored.user_addr, price.user_addr);
+        assert_eq!((stored.rate_per_kb, stored.rate_per_second), (3, 2));
+
+        // sync and async helpers see the same data
+        let all: Vec<(String, UserBandwidthPrice)> = redis.clone().hgetall(k.clone()).unwrap();
+        assert_eq!(all.len(), 1);
+        redis.del_async(k).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_get_user_quests() {
+        let redis = test_redis().await;
+        let user_addr = test_addr("test_get_user_quests");
+        let quests = [
+            DPNRedisKey::get_first_time_provider_kf(user_addr.clone()).unwrap(),
+            DPNRedisKey::get_completed_8_hours_kf(user_addr.clone()).unwrap(),
+            DPNRedisKey::get_completed_time_per_day_kf(user_addr.clone()).unwrap(),
+            DPNRedisKey::get_invite_friend_kf(user_addr.clone()).unwrap(),
+        ];
+        for (i, (k, f)) in quests.into_iter().enumerate() {
+            if i % 2 == 0 {
+                redis.clone().hset(k, f, true).unwrap();
+            } else {
+                redis.clone().hdel(k, f).unwrap();
+            }
+        }
+
+        let summary = redis.get_user_quests(user_addr).await.unwrap();
+        assert_eq!(
+            summary,
+            UserQuestSummary {
+                first_time: true,
+                completed_8h: false,
+                completed_per_day: true,
+                invited_friend: false,
+            }
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_user_geos() {
+        let redis = test_redis().await;
+        let entries: Vec<(String, u64)> = (0..3)
+            .map(|i| (format!("0xtest_user_geo_{}", i), 1562822 + i))
+            .collect();
+        redis.clone().set_user_geos(entries.clone()).await.unwrap();
+
+        let geos = redis.clone().get_all_user_geos().await.unwrap();
+        for (user_addr, geoname_id) in entries {
+            assert_eq!(geos.get(&user_addr), Some(&geoname_id));
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_create_session() {
+        let redis = test_redis_prefixed("test_create_session").await;
+        let mut events = redis
+            .clone()
+            .psubscribe(DPNRedisKey::get_session_events_chan())
+            .await
+            .unwrap();
+        let session = EphemeralSession::new(
+            "test_create_session".to_owned(),
+            test_addr("test_create_session_client"),
+            test_addr("test_create_session_peer"),
+            3,
+            2,
+            "login_session_id".to_owned(),
+        );
+        assert!(redis
+            .clone()
+            .try_open_session(session.client_addr.clone(), 1)
+            .await
+            .unwrap());
+        redis
+            .clone()
+            .create_session("masternode".to_owned(), session.clone())
+            .await
+            .unwrap();
+
+        let stored = redis
+            .clone()
+            .get_session(session.hash.clone())
+            .await
+            .unwrap();
+        assert_eq!(stored.map(|s| s.hash), Some(session.hash.clone()));
+        let peer_sessions = redis
+            .clone()
+            .get_sessions_for_peer(session.peer_addr.clone())
+            .await
+            .unwrap();
+        assert!(peer_sessions.contains(&session.hash));
+        assert!(!redis
+            .clone()
+            .try_open_session(session.client_addr.clone(), 1)
+            .await
+            .unwrap());
+        let active_bandwidth = redis.clone().total_active_bandwidth().await.unwrap();
+        assert!(redis
+            .clone()
+            .update_session_bandwidth(session.hash.clone(), 2048)
+            .await
+            .unwrap());
+        assert_eq!(
+            redis.clone().total_active_bandwidth().await.unwrap(),
+            active_bandwidth + 2048
+        );
+
+        let (_, payload) = tokio::time::timeout(Duration::from_secs(5), events.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        match serde_json::from_slice::<DPNEvent>(&payload).unwrap() {
+            DPNEvent::SessionCreated(extra) => assert_eq!(extra.session.hash, session.hash),
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        redis
+            .clone()
+            .terminate_session(
+                "masternode".to_owned(),
+                session.clone(),
+                SessionTerminationReason::ClientInactive,
+            )
+            .await
+            .unwrap();
+        assert!(redis
+            .clone()
+            .get_session(session.hash.clone())
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            redis.clone().total_active_bandwidth().await.unwrap(),
+            active_bandwidth
+        );
+        assert!(redis
+            .clone()
+            .try_open_session(session.client_addr.clone(), 1)
+            .await
+            .unwrap());
+        redis
+            .clone()
+            .close_session(session.client_addr.clone())
+            .await
+            .unwrap();
+        assert!(!redis
+            .update_session_bandwidth(session.hash, 4096)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_session_ttl() {
+        let redis = test_redis().await;
+        let session = EphemeralSession::new(
+            "test_session_ttl".to_owned(),
+            test_addr("test_session_ttl_client"),
+            test_addr("test_session_ttl_peer"),
+            3,
+            2,
+            "login_session_id".to_owned(),
+        );
+        redis
+            .clone()
+            .create_session_with_ttl(
+                "masternode".to_owned(),
+                session.clone(),
+                Duration::from_millis(500),
+            )
+            .await
+            .unwrap();
+        let stored = redis.clone().get_session(session.hash.clone()).await;
+        assert!(stored.unwrap().is_some());
+        let sessions = redis
+            .clone()
+            .get_client_sessions(session.client_addr.clone())
+            .await;
+        assert!(sessions.unwrap().contains(&session.hash));
+
+        tokio::time::sleep(Duration::from_millis(800)).await;
+        let stored = redis.clone().get_session(session.hash.clone()).await;
+        assert!(stored.unwrap().is_none());
+        let sessions = redis
+            .clone()
+            .get_client_sessions(session.client_addr.clone())
+            .await;
+        assert!(!sessions.unwrap().contains(&session.hash));
+
+        // rejected before anything is stored or published
+        assert!(redis
+            .clone()
+            .create_session_with_ttl("masternode".to_owned(), session.clone(), Duration::ZERO)
+            .await
+            .is_err());
+        let stored = redis.get_session(session.hash).await;
+        assert!(stored.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_client_sessions_index() {
+        let redis = test_redis().await;
+        let session = EphemeralSession::new(
+            "test_client_sessions_index".to_owned(),
+            test_addr("test_client_sessions_index_client"),
+            test_addr("test_client_sessions_index_peer"),
+            3,
+            2,
+            "login_session_id".to_owned(),
+        );
+        let client_sessions = || {
+            redis
+                .clone()
+                .get_client_sessions(session.client_addr.clone())
+        };
+
+        redis
+            .clone()
+            .create_session("masternode".to_owned(), session.clone())
+            .await
+            .unwrap();
+        assert!(client_sessions().await.unwrap().contains(&session.hash));
+
+        redis
+            .clone()
+            .terminate_session(
+                "masternode".to_owned(),
+                session.clone(),
+                SessionTerminationReason::ClientInactive,
+            )
+            .await
+            .unwrap();
+        assert!(!client_sessions().await.unwrap().contains(&session.hash));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_get_sessions_for_peer() {
+        let redis = test_redis().await;
+        let peer_addr = test_addr("test_get_sessions_for_peer");
+        redis
+            .clone()
+            .del(DPNRedisKey::get_peer_sessions_k(peer_addr.clone()).unwrap())
+            .unwrap();
+        let sessions: Vec<EphemeralSession> = ["client_a", "client_b"]
+            .into_iter()
+            .map(|name| {
+                EphemeralSession::new(
+                    test_addr(name),
+                    test_addr(name),
+                    peer_addr.clone(),
+                    3,
+                    2,
+                    "login_session_id".to_owned(),
+                )
+            })
+            .collect();
+        for session in sessions.iter() {
+            redis
+                .clone()
+                .create_session("masternode".to_owned(), session.clone())
+                .await
+                .unwrap();
+        }
+
+        // peer disconnects, both of its sessions are found and terminated
+        let mut hashes = redis
+            .clone()
+            .get_sessions_for_peer(peer_addr.clone())
+            .await
+            .unwrap();
+        hashes.sort();
+        let mut expected: Vec<String> = sessions.iter().map(|s| s.hash.clone()).collect();
+        expected.sort();
+        assert_eq!(hashes, expected);
+
+        for session in sessions {
+            redis
+                .clone()
+                .terminate_session(
+                    "masternode".to_owned(),
+                    session,
+                    SessionTerminationReason::PeerDisconnected,
+                )
+                .await
+                .unwrap();
+        }
+        let hashes = redis.get_sessions_for_peer(peer_addr).await.unwrap();
+        assert!(hashes.is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_flush_and_close() {
+        let redis = test_redis().await;
+        let shared = redis.clone();
+        assert!(redis.flush_and_close().await.is_err());
+        shared.flush_and_close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_subscribe_raw() {
+        let redis = test_redis().await;
+        let chan = "test_subscribe_raw".to_owned();
+        let mut messages = redis.clone().subscribe_raw(chan.clone()).await.unwrap();
+
+        // not valid utf-8, so it cannot go through the json helpers
+        let bz = vec![0x00, 0xff, 0xfe, 0x1f, 0x8b];
+        let mut conn = redis.clone().get_conn().await.unwrap();
+        conn.publish::<_, _, ()>(&chan, &bz).unwrap();
+
+        let msg = tokio::time::timeout(Duration::from_secs(5), messages.next())
+            .await
+            .unwrap();
+        assert_eq!(msg.map(|msg| msg.unwrap()), Some(bz));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_psubscribe_peers_of_every_masternode() {
+        let redis = test_redis().await;
+        let mut messages = redis
+            .clone()
+            .psubscribe(DPNRedisKey::get_peers_chan_pattern())
+            .await
+            .unwrap();
+
+        for masternode_id in ["test_psubscribe_ms1", "test_psubscribe_ms2"] {
+            redis
+                .clone()
+                .publish(
+                    DPNRedisKey::get_peers_chan(masternode_id.to_owned()).unwrap(),
+                    masternode_id.to_owned(),
+                )
+                .await
+                .unwrap();
+        }
+
+        let mut received = vec![];
+        while received.len() < 2 {
+            let (channel, payload) = tokio::time::timeout(Duration::from_secs(5), messages.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            // other masternodes may publish on the same redis
+            let masternode_id = DPNRedisKey::parse_peers_chan(&channel).unwrap();
+            if masternode_id.starts_with("test_psubscribe_") {
+                assert_eq!(payload, masternode_id.as_bytes());
+                received.push(masternode_id);
+            }
+        }
+        assert_eq!(received, vec!["test_psubscribe_ms1", "test_psubscribe_ms2"]);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_publish_idempotent() {
+        let redis = test_redis().await;
+        let publish = |message_id: &str| {
+            redis.clone().publish_idempotent(
+                "test_publish_idempotent".to_owned(),
+                message_id.to_owned(),
+                "{}".to_owned(),
+                Duration::from_secs(5),
+            )
+        };
+        let message_id = format!("{}", chrono::Utc::now().timestamp_micros());
+        assert!(publish(&message_id).await.unwrap());
+        assert!(!publish(&message_id).await.unwrap());
+        assert!(publish(&format!("{}_other", message_id)).await.unwrap());
+        assert!(redis
+            .clone()
+            .publish_idempotent(
+                "test_publish_idempotent".to_owned(),
+                format!("{}_zero_ttl", message_id),
+                "{}".to_owned(),
+                Duration::ZERO,
+            )
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_heartbeat_peer() {
+        let redis = test_redis().await;
+        let masternode_id = "test_heartbeat_peer".to_owned();
+        let queue_k = DPNRedisKey::get_peer_queue_k(masternode_id.clone()).unwrap();
+        redis.clone().del(queue_k.clone()).unwrap();
+        redis
+            .clone()
+            .zadd(queue_k.clone(), TimestampScore::from_timestamp(100), 1)
+            .unwrap();
+
+        redis
+            .clone()
+            .heartbeat_peer(masternode_id.clone(), 1, 200)
+            .await
+            .unwrap();
+        // an older heartbeat does not move the score back
+        redis
+            .clone()
+            .heartbeat_peer(masternode_id.clone(), 1, 150)
+            .await
+            .unwrap();
+        // a peer outside the queue is not added
+        redis
+            .clone()
+            .heartbeat_peer(masternode_id.clone(), 2, 200)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            redis
+                .clone()
+                .zgetall::<TimestampScore>(queue_k.clone())
+                .unwrap(),
+            vec![(1, TimestampScore::from_timestamp(200))]
+        );
+        assert_eq!(
+            redis
+                .clone()
+                .get_peer_last_seen(masternode_id.clone(), 1)
+                .await
+                .unwrap(),
+            Some(150)
+        );
+        redis.clone().del(queue_k).unwrap();
+        redis.remove_all_peers(masternode_id).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_country_bandwidth() {
+        let redis = test_redis().await;
+        let (vn, sg) = (1562822, 1880251);
+        let vn_before = redis.clone().get_country_bandwidth(vn).await.unwrap();
+        let sg_before = redis.clone().get_country_bandwidth(sg).await.unwrap();
+
+        redis
+            .clone()
+            .record_session_country_bandwidth(vn, 100)
+            .await
+            .unwrap();
+        redis
+            .clone()
+            .record_session_country_bandwidth(sg, 30)
+            .await
+            .unwrap();
+        redis
+            .clone()
+            .record_session_country_bandwidth(vn, 20)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            redis.clone().get_country_bandwidth(vn).await.unwrap(),
+            vn_before + 120
+        );
+        assert_eq!(
+            redis.clone().get_country_bandwidth(sg).await.unwrap(),
+            sg_before + 30
+        );
+    }
+
+    #[test]
+    fn test_plan_price_dedupe() {
+        let addr = "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed";
+        let lower = addr.to_lowercase();
+        let other = "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359";
+        let price = |user_addr: &str, rate: i64, updated_at: i64| {
+            (
+                user_addr.to_owned(),
+                UserBandwidthPrice {
+                    user_addr: user_addr.to_owned(),
+                    rate_per_kb: rate,
+                    rate_per_second: rate,
+                    updated_at,
+                },
+            )
+        };
+        // the lowercase price is the most recent
+        let (latest, removed) = plan_price_dedupe(vec![
+            price(addr, 20, 200),
+            price(&lower, 30, 300),
+            price(other, 5, 100),
+        ]);
+        assert_eq!(removed, vec![lower]);
+        assert_eq!(latest.len(), 1);
+        assert_eq!(latest[0].user_addr, addr);
+        assert_eq!((latest[0].rate_per_kb, latest[0].updated_at), (30, 300));
+
+        let (latest, removed) = plan_price_dedupe(vec![price(other, 5, 100)]);
+        assert!(latest.is_empty() && removed.is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_pooled_hset_concurrency() {
+        let uri = test_redis_uri();
+        let redis = Arc::new(RedisService::with_pool_size(uri, 4).await.unwrap());
+        let k = "test_pooled_hset_concurrency".to_owned();
+        redis.clone().del(k.clone()).unwrap();
+
+        // far more callers than pooled connections, they wait for a free one
+        std::thread::scope(|s| {
+            let handles: Vec<_> = (0..64)
+                .map(|i| {
+                    let (redis, k) = (redis.clone(), k.clone());
+                    s.spawn(move || redis.hset(k, i.to_string(), i))
+                })
+                .collect();
+            for handle in handles {
+                handle.join().unwrap().unwrap();
+            }
+        });
+        let all: Vec<(String, u32)> = redis.clone().hgetall(k.clone()).unwrap();
+        assert_eq!(all.len(), 64);
+        redis.del(k).unwrap();
+    }
+
+    #[test]
+    fn test_pubsub_payload_compression() {
+        let prices = (0..100)
+            .map(|i| {
+                UserBandwidthPrice::new(test_addr(&format!("pubsub_compression_{}", i)), i, i * 2)
+                    .unwrap()
+            })
+            .collect();
+        let bz = serde_json::to_vec(&PriceBatchUpdated(prices)).unwrap();
+        let compressed = encode_pubsub_payload(bz.clone(), Some(1024)).unwrap();
+        assert_eq!(compressed[0], PUBSUB_GZIP_HEADER);
+        assert!(compressed.len() < bz.len());
+        assert_eq!(encode_pubsub_payload(bz.clone(), None).unwrap(), bz);
+
+        for payload in [bz.clone(), compressed] {
+            let decoded = decode_pubsub_payload(&payload).unwrap();
+            let batch: PriceBatchUpdated = serde_json::from_slice(&decoded).unwrap();
+            assert_eq!(batch.0.len(), 100);
+        }
+        // payloads without the header are returned as is, even gzip ones
+        let gzipped = gzip_above(bz.clone(), Some(1024)).unwrap();
+        for raw in [vec![0x00, 0x02], gzipped] {
+            assert_eq!(decode_pubsub_payload(&raw).unwrap(), raw);
+        }
+        assert!(decode_pubsub_payload(&[PUBSUB_GZIP_HEADER, 0x02]).is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_publish_compressed() {
+        let uri = test_redis_uri();
+        let config = RedisConfig {
+            uri,
+            pubsub_compress_threshold: Some(16),
+            ..Default::default()
+        };
+        let redis = Arc::new(RedisService::from_config(config).await.unwrap());
+        let chan = "test_publish_compressed".to_owned();
+        let mut messages = redis.clone().subscribe_raw(chan.clone()).await.unwrap();
+
+        let payload = "x".repeat(4096);
+        redis.clone().publish(chan, payload.clone()).await.unwrap();
+
+        // subscribe_raw inflates the compressed payload
+        let msg = tokio::time::timeout(Duration::from_secs(5), messages.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(msg, payload.as_bytes());
+    }
+
+    #[test]
+    fn test_render_gauges() {
+        let peers = BTreeMap::from([("ms_1".to_owned(), 3), ("ms\"2".to_owned(), 0)]);
+        let out = render_gauges(&peers, 5, 2048);
+        let lines: Vec<&str> = out.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "dpn_active_peers{masternode=\"ms\\\"2\"} 0",
+                "dpn_active_peers{masternode=\"ms_1\"} 3",
+                "dpn_active_sessions 5",
+                "dpn_active_bandwidth_bytes 2048",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_peers() {
+        let peer = |uuid: &str, ip_u32: u32| PeerChangedInfo {
+            uuid: uuid.to_owned(),
+            login_session_id: "login_session_id".to_owned(),
+            ip_u32,
+        };
+        let current = vec![peer("a", 1), peer("b", 2), peer("stale", 3)];
+        let actual = vec![peer("a", 1), peer("c", 3), peer("d", 4)];
+
+        let (missing, orphans) = diff_peers(&current, &actual);
+        assert_eq!(missing, vec![peer("c", 3), peer("d", 4)]);
+        assert_eq!(orphans, vec![peer("b", 2), peer("stale", 3)]);
+    }
+
+    #[test]
+    fn test_parse_peers_chan() {
+        let chan = DPNRedisKey::get_peers_chan("ms1".to_owned()).unwrap();
+        assert_eq!(DPNRedisKey::parse_peers_chan(&chan), Some("ms1".to_owned()));
+        assert_eq!(DPNRedisKey::parse_peers_chan("peers_updated_ms#"), None);
+        assert_eq!(DPNRedisKey::parse_peers_chan("price_updated"), None);
+    }
+
+    #[test]
+    fn test_key_builders_reject_empty_ids() {
+        let e = DPNRedisKey::get_peers_kf("".to_owned(), 0).unwrap_err();
+        assert!(e.to_string().contains("masternode_id must not be empty"));
+        assert!(DPNRedisKey::get_peers_chan("".to_owned()).is_err());
+        assert!(DPNRedisKey::get_geo_kf("".to_owned(), "login_session_id".to_owned()).is_err());
+        assert!(DPNRedisKey::get_user_addr_geo_kf("".to_owned()).is_err());
+        assert!(DPNRedisKey::get_session_k("".to_owned()).is_err());
+        assert!(DPNRedisKey::get_peers_kf("masternode".to_owned(), 0).is_ok());
+    }
+
+    #[test]
+    fn test_address_keys() {
+        let addr = "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed";
+        // balances and prices keep their stored fields
+        assert_eq!(
+            DPNRedisKey::get_balance_kf(addr.to_lowercase()).unwrap().1,
+            addr.to_lowercase()
+        );
+        assert_eq!(DPNRedisKey::get_price_kf(addr.to_owned()).unwrap().1, addr);
+        assert_eq!(
+            DPNRedisKey::get_client_sessions_k(addr.to_lowercase()).unwrap(),
+            format!("sessions_by_client#{}", addr)
+        );
+        assert_eq!(
+            DPNRedisKey::get_peer_sessions_k(addr.to_lowercase()).unwrap(),
+            format!("sessions_by_peer#{}", addr)
+        );
+
+        assert!(DPNRedisKey::get_balance_kf("".to_owned()).is_err());
+        assert!(DPNRedisKey::get_price_kf("".to_owned()).is_err());
+        // a mixed case address with a wrong checksum is a typo, not another casing
+        assert!(DPNRedisKey::get_client_sessions_k(
+            "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAeD".to_owned()
+        )
+        .is_err());
     }
 }
@@ -0,0 +1,72 @@
+use std::{sync::Arc, time::Duration};
+
+use log::{debug, warn};
+
+use super::redis::RedisService;
+
+/// default min interval between accepted speed tests of a peer
+pub const DEFAULT_SPEED_TEST_INTERVAL: Duration = Duration::from_secs(60);
+
+/// accept at most one speed test per peer every `min_interval`, shared by all masternodes
+/// a zero `min_interval` accepts every test
+#[derive(Debug)]
+pub struct SpeedTestThrottle {
+    redis: Arc<RedisService>,
+    min_interval: Duration,
+}
+
+impl SpeedTestThrottle {
+    pub fn new(redis: Arc<RedisService>, min_interval: Duration) -> Self {
+        Self {
+            redis,
+            min_interval,
+        }
+    }
+
+    /// false when the peer had a test accepted less than `min_interval` ago
+    /// tests are accepted when redis fails, so an outage does not drop every result
+    pub async fn should_accept(&self, ip_u32: u32, now: i64) -> bool {
+        match self
+            .redis
+            .clone()
+            .try_accept_speed_test(ip_u32, now, self.min_interval)
+            .await
+        {
+            Ok(true) => true,
+            Ok(false) => {
+                debug!("speed test throttled ip_u32={} now={}", ip_u32, now);
+                false
+            }
+            Err(e) => {
+                warn!("speed test throttle failed ip_u32={} err={}", ip_u32, e);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::redis::tests::test_redis;
+
+    #[tokio::test]
+    #[ignore = "requires a running redis at REDIS_URI"]
+    async fn test_should_accept() {
+        let redis = test_redis().await;
+        let throttle = SpeedTestThrottle::new(redis, Duration::from_millis(200));
+        let ip_u32 = chrono::Utc::now().timestamp_micros() as u32;
+        let now = chrono::Utc::now().timestamp();
+
+        assert!(throttle.should_accept(ip_u32, now).await);
+        assert!(!throttle.should_accept(ip_u32, now).await);
+        assert!(throttle.should_accept(ip_u32 ^ 1, now).await);
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(throttle.should_accept(ip_u32, now).await);
+
+        let unthrottled = SpeedTestThrottle::new(throttle.redis.clone(), Duration::ZERO);
+        assert!(unthrottled.should_accept(ip_u32, now).await);
+        assert!(unthrottled.should_accept(ip_u32, now).await);
+    }
+}
@@ -1,6 +1,13 @@
+use std::{
+    fmt::Debug,
+    net::{AddrParseError, Ipv4Addr},
+};
+
 use serde::{Deserialize, Serialize};
 
-use crate::types::connection::ProxyAccData;
+use crate::types::{
+    bandwidth::UserBandwidthPrice, connection::ProxyAccData, msg_queue::PeerConnectedExtra,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum PeerChanged {
@@ -8,13 +15,118 @@ pub enum PeerChanged {
     Disconnected(PeerChangedInfo),
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PeerChangedInfo {
     pub uuid: String,
     pub login_session_id: String,
     pub ip_u32: u32,
 }
 
+/// redis peer info is derived from the broker event so both channels carry the same peer
+/// fails when peer ip is not a valid ipv4 address
+impl TryFrom<&PeerConnectedExtra> for PeerChangedInfo {
+    type Error = AddrParseError;
+
+    fn try_from(extra: &PeerConnectedExtra) -> Result<Self, Self::Error> {
+        let ip_addr = extra.info.ip_addr.parse::<Ipv4Addr>()?;
+        Ok(Self {
+            uuid: extra.info.peer_id.clone(),
+            login_session_id: extra.login_session_id.clone(),
+            ip_u32: u32::from(ip_addr),
+        })
+    }
+}
+
+pub(crate) mod sealed {
+    pub trait Sealed {
+        /// score stored in redis, only the queue helpers decode it into the kind they read
+        fn from_raw(score: u32) -> Self;
+    }
+}
+
+/// score of a peer in the peer queue sorted set, peers with lower raw score are served first
+/// the kind of score is the type, so a queue is written and read as the same kind,
+/// timestamps and priorities never compare
+pub trait PeerScore: sealed::Sealed + Copy + Ord + Debug {
+    /// raw score stored in redis
+    fn raw(&self) -> u32;
+}
+
+/// fifo, peers queued earlier are served first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct TimestampScore(u32);
+
+impl TimestampScore {
+    /// `ts` is unix secs
+    pub fn from_timestamp(ts: u32) -> Self {
+        Self(ts)
+    }
+
+    pub fn as_timestamp(&self) -> u32 {
+        self.0
+    }
+}
+
+impl sealed::Sealed for TimestampScore {
+    fn from_raw(score: u32) -> Self {
+        Self(score)
+    }
+}
+
+impl PeerScore for TimestampScore {
+    fn raw(&self) -> u32 {
+        self.0
+    }
+}
+
+/// peers with higher priority are served first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct PriorityScore(u32);
+
+impl PriorityScore {
+    pub fn from_priority(priority: u32) -> Self {
+        Self(u32::MAX - priority)
+    }
+
+    pub fn as_priority(&self) -> u32 {
+        u32::MAX - self.0
+    }
+}
+
+impl sealed::Sealed for PriorityScore {
+    fn from_raw(score: u32) -> Self {
+        Self(score)
+    }
+}
+
+impl PeerScore for PriorityScore {
+    fn raw(&self) -> u32 {
+        self.0
+    }
+}
+
+/// result of `RedisService::reconcile_peers`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ReconcileReport {
+    /// connected peers missing in redis
+    pub added: usize,
+    /// peers in redis that are not connected
+    pub removed: usize,
+}
+
+/// prices updated together, published on price batch channel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PriceBatchUpdated(pub Vec<UserBandwidthPrice>);
+
+/// quests completed by a user, result of `RedisService::get_user_quests`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserQuestSummary {
+    pub first_time: bool,
+    pub completed_8h: bool,
+    pub completed_per_day: bool,
+    pub invited_friend: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ProxyAccChanged {
     Created(ProxyAccData),
@@ -22,3 +134,49 @@ pub enum ProxyAccChanged {
     Deleted(String), // proxy_acc_id
     RefreshAll(),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::connection::PeernodeInfo;
+
+    #[test]
+    fn test_peer_changed_info_from_extra() {
+        let mut extra = PeerConnectedExtra {
+            masternode_id: "masternode".to_owned(),
+            peer_addr: "0xpeer".to_owned(),
+            login_session_id: "login_session_id".to_owned(),
+            info: PeernodeInfo {
+                peer_id: "peer_id".to_owned(),
+                ip_addr: "1.2.3.4".to_owned(),
+                throughput: 0.0,
+                rate_per_kb: 0,
+                rate_per_second: 0,
+                city_geoname_id: 0,
+                country_geoname_id: 0,
+            },
+        };
+        let info = PeerChangedInfo::try_from(&extra).unwrap();
+        assert_eq!(info.uuid, extra.info.peer_id);
+        assert_eq!(info.ip_u32, 0x01020304);
+
+        extra.info.ip_addr = "::1".to_owned();
+        assert!(PeerChangedInfo::try_from(&extra).is_err());
+    }
+
+    #[test]
+    fn test_peer_score_order() {
+        use sealed::Sealed as _;
+
+        assert!(TimestampScore::from_timestamp(100) < TimestampScore::from_timestamp(200));
+        assert!(PriorityScore::from_priority(10) < PriorityScore::from_priority(1));
+        assert_eq!(PriorityScore::from_priority(7).as_priority(), 7);
+        let score = TimestampScore::from_timestamp(1_700_000_000);
+        assert_eq!(
+            TimestampScore::from_raw(score.raw()).as_timestamp(),
+            1_700_000_000
+        );
+        let score = PriorityScore::from_priority(7);
+        assert_eq!(PriorityScore::from_raw(score.raw()), score);
+    }
+}
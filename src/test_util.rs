@@ -0,0 +1,152 @@
+use crate::types::{
+    bandwidth::{EphemeralSession, SessionTerminationReason},
+    connection::PeernodeInfo,
+    msg_queue::{
+        DPNEvent, DepositExtra, PeerConnectedExtra, PeerDisconnectedExtra, SessionCreatedExtra,
+        SessionTerminatedExtra,
+    },
+};
+use crate::utils::{bytes_to_hex_string, hash::hash};
+
+/// builds a consistent event flow for integration tests:
+/// deposit -> peer connected -> session created -> session terminated -> peer disconnected
+/// session events share one session (same hash), terminated session carries duration and bandwidth
+#[derive(Debug, Clone)]
+pub struct EventScenario {
+    client_addr: String,
+    peer_addr: String,
+    masternode_id: String,
+    login_session_id: String,
+    rate_per_kb: u64,
+    rate_per_second: u64,
+    deposit: u64,
+    duration: i64,
+    bandwidth_usage: u64,
+    reason: SessionTerminationReason,
+}
+
+impl EventScenario {
+    pub fn new(client_addr: String, peer_addr: String, masternode_id: String) -> Self {
+        Self {
+            login_session_id: format!("login_{}", peer_addr),
+            client_addr,
+            peer_addr,
+            masternode_id,
+            rate_per_kb: 1,
+            rate_per_second: 1,
+            deposit: 1_000_000,
+            duration: 60,
+            bandwidth_usage: 1024 * 1024,
+            reason: SessionTerminationReason::ClientInactive,
+        }
+    }
+
+    pub fn rates(mut self, rate_per_kb: u64, rate_per_second: u64) -> Self {
+        self.rate_per_kb = rate_per_kb;
+        self.rate_per_second = rate_per_second;
+        self
+    }
+
+    pub fn deposit(mut self, amount: u64) -> Self {
+        self.deposit = amount;
+        self
+    }
+
+    /// session duration in seconds
+    pub fn duration(mut self, duration: i64) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    /// bytes used by the session
+    pub fn bandwidth_usage(mut self, bandwidth_usage: u64) -> Self {
+        self.bandwidth_usage = bandwidth_usage;
+        self
+    }
+
+    pub fn reason(mut self, reason: SessionTerminationReason) -> Self {
+        self.reason = reason;
+        self
+    }
+
+    /// session addresses are normalized by `EphemeralSession::new`, invalid ones are kept as is
+    pub fn build(self) -> Vec<DPNEvent> {
+        let session = EphemeralSession::new(
+            self.client_addr.clone(),
+            self.client_addr.clone(),
+            self.peer_addr.clone(),
+            self.rate_per_kb,
+            self.rate_per_second,
+            self.login_session_id.clone(),
+        );
+        let mut ended = session.clone();
+        ended.end_at = session.handshaked_at + self.duration;
+        ended.bandwidth_usage = self.bandwidth_usage;
+
+        vec![
+            DPNEvent::Deposit(DepositExtra {
+                from: self.client_addr.clone(),
+                to: self.client_addr.clone(),
+                amount: self.deposit,
+                tx_hash: bytes_to_hex_string(
+                    hash(format!("deposit:{}", session.hash).as_bytes()).as_bytes(),
+                ),
+            }),
+            DPNEvent::PeerConnected(PeerConnectedExtra {
+                masternode_id: self.masternode_id.clone(),
+                peer_addr: self.peer_addr.clone(),
+                login_session_id: self.login_session_id.clone(),
+                info: PeernodeInfo {
+                    peer_id: self.peer_addr.clone(),
+                    ip_addr: "127.0.0.1".to_owned(),
+                    throughput: 0.0,
+                    rate_per_kb: self.rate_per_kb,
+                    rate_per_second: self.rate_per_second,
+                    city_geoname_id: 0,
+                    country_geoname_id: 0,
+                },
+            }),
+            DPNEvent::SessionCreated(SessionCreatedExtra {
+                masternode_id: self.masternode_id.clone(),
+                session,
+            }),
+            DPNEvent::SessionTerminated(SessionTerminatedExtra {
+                masternode_id: self.masternode_id.clone(),
+                session: ended,
+                reason: self.reason,
+            }),
+            DPNEvent::PeerDisconnected(PeerDisconnectedExtra {
+                masternode_id: self.masternode_id,
+                peer_addr: self.peer_addr,
+                login_session_id: self.login_session_id,
+            }),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_events_share_hash() {
+        let events = EventScenario::new(
+            "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed".to_owned(),
+            "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359".to_owned(),
+            "ms1".to_owned(),
+        )
+        .duration(30)
+        .build();
+        let created = events.iter().find_map(|e| match e {
+            DPNEvent::SessionCreated(extra) => Some(&extra.session),
+            _ => None,
+        });
+        let terminated = events.iter().find_map(|e| match e {
+            DPNEvent::SessionTerminated(extra) => Some(&extra.session),
+            _ => None,
+        });
+        let (created, terminated) = (created.unwrap(), terminated.unwrap());
+        assert_eq!(created.hash, terminated.hash);
+        assert_eq!(terminated.end_at - created.handshaked_at, 30);
+    }
+}
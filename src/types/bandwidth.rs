@@ -1,20 +1,160 @@
-use crate::utils::{bytes_to_hex_string, hash::hash};
+use crate::utils::{address_to_string, bytes_to_hex_string, hash::hash, normalize_address};
+use anyhow::{anyhow, Result};
 use chrono::Utc;
 use dpn_proto::session::ProtoSession;
 use ethers::types::H256;
 use num_derive::FromPrimitive;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use utoipa::ToSchema;
 use web3::types::{Address, U256};
 
+use super::msg_queue::{SessionCreatedExtra, SessionTerminatedExtra};
+
+/// rates and balances are denominated in szabo (10^-6 token)
+pub const FEE_DECIMALS: u32 = 6;
+/// 100% in basis points
+pub const MAX_BPS: u16 = 10_000;
+
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct UserBandwidthPrice {
     pub user_addr: String,
     pub rate_per_kb: i64,
     pub rate_per_second: i64,
+    /// unix timestamp the price was published at, 0 for prices stored before it was tracked
+    #[serde(default)]
+    pub updated_at: i64,
+}
+
+impl UserBandwidthPrice {
+    /// user_addr is normalized to its checksummed form, invalid addresses are rejected
+    pub fn new(user_addr: String, rate_per_kb: i64, rate_per_second: i64) -> Result<Self> {
+        Ok(Self {
+            user_addr: normalize_address(&user_addr)?,
+            rate_per_kb,
+            rate_per_second,
+            updated_at: Utc::now().timestamp(),
+        })
+    }
+
+    /// rates discounted by `adj.discount_bps` (capped at MAX_BPS)
+    /// discounted rates are rounded down, so the partner always gets at least the agreed discount
+    pub fn with_partner_discount(&self, adj: &PartnerRateAdjustment) -> UserBandwidthPrice {
+        let keep_bps = (MAX_BPS - adj.discount_bps.min(MAX_BPS)) as i128;
+        let max_bps = MAX_BPS as i128;
+        let discount = |rate: i64| (rate as i128 * keep_bps / max_bps) as i64;
+        UserBandwidthPrice {
+            user_addr: self.user_addr.clone(),
+            rate_per_kb: discount(self.rate_per_kb),
+            rate_per_second: discount(self.rate_per_second),
+            updated_at: self.updated_at,
+        }
+    }
+}
+
+/// latest price of each provider by `updated_at`, e.g. the same provider stored under
+/// differently cased addresses, keyed by normalized user_addr
+pub fn latest_prices(prices: Vec<UserBandwidthPrice>) -> HashMap<String, UserBandwidthPrice> {
+    let mut latest: HashMap<String, UserBandwidthPrice> = HashMap::new();
+    for mut price in prices {
+        if let Ok(user_addr) = normalize_address(&price.user_addr) {
+            price.user_addr = user_addr;
+        }
+        match latest.get(&price.user_addr) {
+            Some(current) if current.updated_at >= price.updated_at => {}
+            _ => {
+                latest.insert(price.user_addr.clone(), price);
+            }
+        }
+    }
+    latest
+}
+
+/// discount negotiated by a partner, applied to provider rates of sessions created under it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartnerRateAdjustment {
+    pub partner_id: String,
+    pub discount_bps: u16,
 }
 
+/// price changes of a provider ordered by timestamp (secs)
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PriceHistory {
+    pub user_addr: String,
+    pub prices: Vec<(i64, UserBandwidthPrice)>,
+}
+
+impl PriceHistory {
+    pub fn new(user_addr: String) -> Self {
+        Self {
+            user_addr,
+            prices: vec![],
+        }
+    }
+
+    /// record price effective from `at`, a later price for the same `at` replaces the earlier one
+    pub fn push(&mut self, at: i64, price: UserBandwidthPrice) {
+        let idx = self.prices.partition_point(|(ts, _)| *ts <= at);
+        if idx > 0 && self.prices[idx - 1].0 == at {
+            self.prices[idx - 1].1 = price;
+        } else {
+            self.prices.insert(idx, (at, price));
+        }
+    }
+
+    /// time-weighted average rates over [start, end), rounded down
+    /// the first recorded price also applies before it was recorded,
+    /// price at `start` is returned for empty range and zero rates for empty history
+    pub fn avg_price_over(&self, start: i64, end: i64) -> UserBandwidthPrice {
+        let mut avg = UserBandwidthPrice {
+            user_addr: self.user_addr.clone(),
+            rate_per_kb: 0,
+            rate_per_second: 0,
+            updated_at: 0,
+        };
+        if self.prices.is_empty() {
+            return avg;
+        }
+        let idx = self.prices.partition_point(|(ts, _)| *ts <= start).max(1) - 1;
+        if end <= start {
+            avg.rate_per_kb = self.prices[idx].1.rate_per_kb;
+            avg.rate_per_second = self.prices[idx].1.rate_per_second;
+            return avg;
+        }
+
+        let (mut kb_sum, mut second_sum) = (0i128, 0i128);
+        for (i, (ts, price)) in self.prices.iter().enumerate().skip(idx) {
+            let from = if i == idx { start } else { *ts };
+            let to = self.prices.get(i + 1).map_or(end, |(ts, _)| (*ts).min(end));
+            if to <= from {
+                break;
+            }
+            let weight = (to - from) as i128;
+            kb_sum += price.rate_per_kb as i128 * weight;
+            second_sum += price.rate_per_second as i128 * weight;
+        }
+        let duration = (end - start) as i128;
+        avg.rate_per_kb = (kb_sum / duration) as i64;
+        avg.rate_per_second = (second_sum / duration) as i64;
+        avg
+    }
+}
+
+/// number of seconds a client can stay connected with its remaining balance
+/// only duration fee is considered, rounded down so session ends before balance hits zero
+/// returns i64::MAX when provider does not charge per second
+pub fn max_affordable_seconds(balance: i64, price: &UserBandwidthPrice, already_spent: i64) -> i64 {
+    let remaining = balance.saturating_sub(already_spent);
+    if remaining <= 0 {
+        return 0;
+    }
+    if price.rate_per_second <= 0 {
+        return i64::MAX;
+    }
+    remaining / price.rate_per_second
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct EphemeralSession {
     pub hash: String,
     pub client_identifier: String,
@@ -38,6 +178,10 @@ impl EphemeralSession {
         login_session_id: String
     ) -> Self {
         let handshaked_at_micros = Utc::now().timestamp_micros();
+        // same addresses in different casing must produce the same cache keys
+        // invalid addresses are kept as is, use `try_new` to reject them
+        let client_addr = normalize_address(&client_addr).unwrap_or(client_addr);
+        let peer_addr = normalize_address(&peer_addr).unwrap_or(peer_addr);
 
         let mut _self = Self {
             hash: "".to_string(),
@@ -65,6 +209,74 @@ impl EphemeralSession {
         _self.end_at /= 1_000_000;
         _self
     }
+
+    /// same as `new`, but an invalid client_addr or peer_addr is rejected
+    pub fn try_new(
+        client_identifier: String,
+        client_addr: String,
+        peer_addr: String,
+        rate_per_kb: u64,
+        rate_per_second: u64,
+        login_session_id: String,
+    ) -> Result<Self> {
+        Ok(Self::new(
+            client_identifier,
+            normalize_address(&client_addr)?,
+            normalize_address(&peer_addr)?,
+            rate_per_kb,
+            rate_per_second,
+            login_session_id,
+        ))
+    }
+
+    /// reject handshaked_at more than `max_skew_secs` ahead of or behind `now` (unix secs),
+    /// e.g. sent by a peer with a bad clock, it would break duration and fee math
+    pub fn check_clock(&self, now: i64, max_skew_secs: i64) -> Result<()> {
+        let skew = self.handshaked_at.saturating_sub(now);
+        if skew.unsigned_abs() > max_skew_secs.unsigned_abs() {
+            return Err(anyhow!(
+                "session clock skewed hash={} handshaked_at={} now={} skew={}",
+                self.hash,
+                self.handshaked_at,
+                now,
+                skew
+            ));
+        }
+        Ok(())
+    }
+
+    /// fees in szabo as (duration_fee, bandwidth_fee, total_fee)
+    /// bandwidth is billed per full kb of bandwidth_usage bytes
+    pub fn compute_fees(&self) -> (u64, u64, u64) {
+        let (duration_fee, bandwidth_fee) = self.fee_split(self.end_at);
+        (
+            duration_fee,
+            bandwidth_fee,
+            duration_fee.saturating_add(bandwidth_fee),
+        )
+    }
+
+    /// (duration_fee, bandwidth_fee) in szabo as if session ended at `now`, for live display
+    /// negative duration is clamped to 0 and overflowing fee saturates at u64::MAX
+    pub fn fee_split(&self, now: i64) -> (u64, u64) {
+        let duration = now.saturating_sub(self.handshaked_at).max(0) as u64;
+        let duration_fee = duration.saturating_mul(self.rate_per_second);
+        let bandwidth_fee = (self.bandwidth_usage / 1024).saturating_mul(self.rate_per_kb);
+        (duration_fee, bandwidth_fee)
+    }
+
+    /// same as `compute_fees` but scaled to token base units with `decimals`, e.g. 18 for wei
+    pub fn compute_fees_u256(&self, decimals: u32) -> (U256, U256, U256) {
+        let scale = |fee: u64| {
+            if decimals >= FEE_DECIMALS {
+                U256::from(fee) * U256::exp10((decimals - FEE_DECIMALS) as usize)
+            } else {
+                U256::from(fee) / U256::exp10((FEE_DECIMALS - decimals) as usize)
+            }
+        };
+        let (duration_fee, bandwidth_fee, total_fee) = self.compute_fees();
+        (scale(duration_fee), scale(bandwidth_fee), scale(total_fee))
+    }
 }
 
 impl Into<ProtoSession> for EphemeralSession {
@@ -78,12 +290,22 @@ impl Into<ProtoSession> for EphemeralSession {
     }
 }
 
-#[derive(Debug, Clone, FromPrimitive, Serialize, Deserialize, ToSchema)]
+#[derive(Debug, Clone, PartialEq, Eq, FromPrimitive, Serialize, Deserialize, ToSchema)]
 pub enum SessionStatus {
     Active,
     Finished,
 }
 
+impl SessionStatus {
+    /// Active -> Finished is the only allowed transition, Finished is final
+    pub fn can_transition_to(&self, next: &SessionStatus) -> bool {
+        matches!(
+            (self, next),
+            (SessionStatus::Active, SessionStatus::Finished)
+        )
+    }
+}
+
 #[derive(Debug, Clone, FromPrimitive, Serialize, Deserialize, ToSchema)]
 pub enum SessionTerminationReason {
     ClientInactive,
@@ -151,4 +373,724 @@ impl Session {
             tx_hash,
         }
     }
+
+    /// final state of a session folded from its queue events, e.g. to replay it for debugging
+    /// rates and fees are in wei, fees are computed from the terminated session
+    pub fn from_events(
+        created: &SessionCreatedExtra,
+        terminated: &SessionTerminatedExtra,
+    ) -> Result<Session> {
+        let (created_session, session) = (&created.session, &terminated.session);
+        if created_session.hash != session.hash {
+            return Err(anyhow!(
+                "session events mismatch created={} terminated={}",
+                created_session.hash,
+                session.hash
+            ));
+        }
+        let session_hash = session
+            .hash
+            .parse::<H256>()
+            .map_err(|e| anyhow!("invalid session hash={} err={}", session.hash, e))?;
+        let parse_addr = |addr: &str| {
+            addr.parse::<Address>()
+                .map_err(|e| anyhow!("invalid address addr={} err={}", addr, e))
+        };
+        let wei = |szabo: u64| U256::from(szabo) * U256::exp10((18 - FEE_DECIMALS) as usize);
+        let (duration_fee, bandwidth_fee, total_fee) = session.compute_fees_u256(18);
+
+        Ok(Session::new(
+            session_hash,
+            session.client_identifier.clone(),
+            parse_addr(&session.peer_addr)?,
+            parse_addr(&session.client_addr)?,
+            wei(created_session.rate_per_second),
+            wei(created_session.rate_per_kb),
+            Some(created_session.handshaked_at),
+            Some(session.end_at),
+            Some(
+                session
+                    .end_at
+                    .saturating_sub(created_session.handshaked_at)
+                    .max(0),
+            ),
+            Some(session.bandwidth_usage.min(i64::MAX as u64) as i64),
+            duration_fee,
+            bandwidth_fee,
+            total_fee,
+            SessionStatus::Finished,
+            Some(terminated.reason.clone()),
+            None,
+        ))
+    }
+
+    /// move the session to `next`, finalizing sets reason and end_at (if not set yet)
+    pub fn transition(
+        &mut self,
+        next: SessionStatus,
+        reason: Option<SessionTerminationReason>,
+    ) -> Result<()> {
+        if !self.status.can_transition_to(&next) {
+            return Err(anyhow!(
+                "illegal session transition session_hash={:?} from={:?} to={:?}",
+                self.session_hash,
+                self.status,
+                next
+            ));
+        }
+        if next == SessionStatus::Finished {
+            self.reason = reason;
+            self.end_at = self.end_at.or(Some(Utc::now().timestamp()));
+        }
+        self.status = next;
+        Ok(())
+    }
+
+    /// masternode/operator commission taken from total_fee, rounded down
+    pub fn commission(&self, rate_bps: u16) -> Result<U256> {
+        if rate_bps > MAX_BPS {
+            return Err(anyhow!("invalid commission rate_bps={}", rate_bps));
+        }
+        self.total_fee
+            .checked_mul(U256::from(rate_bps))
+            .map(|v| v / U256::from(MAX_BPS))
+            .ok_or(anyhow!(
+                "commission overflow total_fee={} rate_bps={}",
+                self.total_fee,
+                rate_bps
+            ))
+    }
+}
+
+/// Active sessions whose end_at is more than `grace` seconds before `now`
+/// e.g. masternode crashed before termination event was sent, they should be terminated with SystemShutdown
+pub fn find_stale_active_sessions(sessions: &[Session], now: i64, grace: i64) -> Vec<&Session> {
+    sessions
+        .iter()
+        .filter(|s| matches!(s.status, SessionStatus::Active))
+        .filter(|s| matches!(s.end_at, Some(end_at) if end_at.saturating_add(grace) < now))
+        .collect()
+}
+
+pub const SESSIONS_CSV_HEADER: &str = "session_hash,client_identifier,client_addr,provider_addr,\
+rate_per_second,rate_per_kb,handshake_at,end_at,duration,bandwidth_usage,\
+duration_fee,bandwidth_fee,total_fee,status,reason,tx_hash";
+
+/// settlement CSV of sessions, rates and fees in wei, absent values are empty
+/// rows are ordered by (handshake_at, session_hash) so exports of the same sessions are identical
+pub fn sessions_to_csv(sessions: &[Session]) -> String {
+    let mut sorted: Vec<&Session> = sessions.iter().collect();
+    sorted.sort_by_key(|s| (s.handshake_at, s.session_hash));
+
+    let opt = |v: Option<i64>| v.map(|v| v.to_string()).unwrap_or_default();
+    let mut csv = String::from(SESSIONS_CSV_HEADER);
+    csv.push('\n');
+    for s in sorted {
+        let row = [
+            format!("{:?}", s.session_hash),
+            s.client_identifier.clone(),
+            address_to_string(s.client_addr),
+            address_to_string(s.provider_addr),
+            s.rate_per_second.to_string(),
+            s.rate_per_kb.to_string(),
+            opt(s.handshake_at),
+            opt(s.end_at),
+            opt(s.duration),
+            opt(s.bandwidth_usage),
+            s.duration_fee.to_string(),
+            s.bandwidth_fee.to_string(),
+            s.total_fee.to_string(),
+            format!("{:?}", s.status),
+            s.reason
+                .as_ref()
+                .map(|r| format!("{:?}", r))
+                .unwrap_or_default(),
+            s.tx_hash.map(|h| format!("{:?}", h)).unwrap_or_default(),
+        ];
+        let row: Vec<String> = row.iter().map(|f| csv_escape(f)).collect();
+        csv.push_str(&row.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+/// quote a field containing separators, quotes or line breaks (RFC 4180)
+/// a field starting like a spreadsheet formula is prefixed with `'` so it is read as text
+fn csv_escape(field: &str) -> String {
+    let field = if field.starts_with(['=', '+', '-', '@']) {
+        format!("'{}", field)
+    } else {
+        field.to_owned()
+    };
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+/// signed `total_fee - expected fee` in szabo (rounded toward zero), expected fee is
+/// recomputed from rates and usage like `EphemeralSession::compute_fees`
+/// None when duration or bandwidth_usage is absent or the expected fee overflows
+pub fn fee_variance(session: &Session) -> Option<i64> {
+    let duration = U256::from(session.duration?.max(0) as u64);
+    let kbs = U256::from((session.bandwidth_usage?.max(0) as u64) / 1024);
+    let expected = duration
+        .checked_mul(session.rate_per_second)?
+        .checked_add(kbs.checked_mul(session.rate_per_kb)?)?;
+    let wei_per_szabo = U256::exp10((18 - FEE_DECIMALS) as usize);
+    let szabo = |wei: U256| (wei / wei_per_szabo).min(U256::from(i64::MAX)).as_u64() as i64;
+    if session.total_fee >= expected {
+        Some(szabo(session.total_fee - expected))
+    } else {
+        Some(-szabo(expected - session.total_fee))
+    }
+}
+
+/// sessions whose fee variance exceeds `tolerance` szabo either way, sessions without
+/// usage fields are skipped
+pub fn sessions_with_variance(sessions: &[Session], tolerance: i64) -> Vec<&Session> {
+    sessions
+        .iter()
+        .filter(
+            |s| matches!(fee_variance(s), Some(v) if v.unsigned_abs() > tolerance.unsigned_abs()),
+        )
+        .collect()
+}
+
+/// billing period aligned to UTC, e.g. `Day` starts at midnight
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BillingPeriod {
+    Hour,
+    Day,
+}
+
+impl BillingPeriod {
+    pub fn secs(&self) -> i64 {
+        match self {
+            BillingPeriod::Hour => 3600,
+            BillingPeriod::Day => 24 * 3600,
+        }
+    }
+
+    /// start of the period containing unix timestamp `ts` (secs)
+    pub fn start_of(&self, ts: i64) -> i64 {
+        ts - ts.rem_euclid(self.secs())
+    }
+}
+
+/// part of a session billed in the period starting at `period_start`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionSlice {
+    pub session_hash: H256,
+    pub period_start: i64,
+    pub start_at: i64,
+    pub end_at: i64,
+    pub bandwidth_usage: i64,
+    pub duration_fee: U256,
+    pub bandwidth_fee: U256,
+    pub total_fee: U256,
+}
+
+/// split session at period boundaries, usage and fees are divided in proportion to the time
+/// spent in each period, rounded down with the remainder going to the last slice so slices
+/// sum back to the session
+/// handshake_at/end_at are unix secs, empty when the session has no handshake_at or end_at
+pub fn split_session_by_period(session: &Session, period: BillingPeriod) -> Vec<SessionSlice> {
+    let (Some(start), Some(end)) = (session.handshake_at, session.end_at) else {
+        return vec![];
+    };
+    let end = end.max(start);
+    let mut bounds = vec![];
+    let mut at = start;
+    loop {
+        let next = period.start_of(at).saturating_add(period.secs());
+        if next >= end {
+            bounds.push((at, end));
+            break;
+        }
+        bounds.push((at, next));
+        at = next;
+    }
+
+    // a session without duration has a single slice, weighted 1 to take everything
+    let total = U256::from((end - start).max(1));
+    let weight = |(from, to): (i64, i64)| U256::from((to - from).max(1));
+    // `value * w / total` without overflowing
+    let share = |value: U256, w: U256| value / total * w + value % total * w / total;
+    let usage = U256::from(session.bandwidth_usage.unwrap_or_default().max(0));
+
+    let mut rest = (
+        usage,
+        session.duration_fee,
+        session.bandwidth_fee,
+        session.total_fee,
+    );
+    let last = bounds.len() - 1;
+    bounds
+        .into_iter()
+        .enumerate()
+        .map(|(i, (from, to))| {
+            let parts = if i == last {
+                rest
+            } else {
+                let w = weight((from, to));
+                (
+                    share(usage, w),
+                    share(session.duration_fee, w),
+                    share(session.bandwidth_fee, w),
+                    share(session.total_fee, w),
+                )
+            };
+            rest = (
+                rest.0 - parts.0,
+                rest.1 - parts.1,
+                rest.2 - parts.2,
+                rest.3 - parts.3,
+            );
+            SessionSlice {
+                session_hash: session.session_hash,
+                period_start: period.start_of(from),
+                start_at: from,
+                end_at: to,
+                bandwidth_usage: parts.0.as_u64() as i64,
+                duration_fee: parts.1,
+                bandwidth_fee: parts.2,
+                total_fee: parts.3,
+            }
+        })
+        .collect()
+}
+
+/// highest number of sessions each peer served at the same time
+/// a session occupies [handshaked_at, end_at), at least one second so sessions still active
+/// (end_at == handshaked_at) are counted, back to back sessions do not overlap
+pub fn max_concurrent_sessions(sessions: &[EphemeralSession]) -> HashMap<String, usize> {
+    let mut edges: HashMap<&str, Vec<(i64, i64)>> = HashMap::new();
+    for session in sessions {
+        let end_at = session.end_at.max(session.handshaked_at.saturating_add(1));
+        let peer_edges = edges.entry(session.peer_addr.as_str()).or_default();
+        peer_edges.push((session.handshaked_at, 1));
+        peer_edges.push((end_at, -1));
+    }
+
+    edges
+        .into_iter()
+        .map(|(peer_addr, mut peer_edges)| {
+            // at the same second a session ends before the next one starts
+            peer_edges.sort();
+            let (mut current, mut max) = (0i64, 0i64);
+            for (_, delta) in peer_edges {
+                current += delta;
+                max = max.max(current);
+            }
+            (peer_addr.to_owned(), max as usize)
+        })
+        .collect()
+}
+
+/// bytes used by `client_addr` across all its `sessions`
+pub fn client_total_bandwidth(sessions: &[EphemeralSession], client_addr: &str) -> Result<u64> {
+    // session addresses are normalized on creation
+    let client_addr = normalize_address(client_addr)?;
+    Ok(sessions
+        .iter()
+        .filter(|s| s.client_addr == client_addr)
+        .fold(0u64, |total, s| total.saturating_add(s.bandwidth_usage)))
+}
+
+/// fair-use daily bandwidth cap of a client
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientQuota {
+    pub daily_kb: u64,
+}
+
+/// whether `used` bytes exceed `quota`
+pub fn is_over_quota(used: u64, quota: &ClientQuota) -> bool {
+    used > quota.daily_kb.saturating_mul(1024)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) const CLIENT_ADDR: &str = "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed";
+    pub(crate) const PEER_ADDR: &str = "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359";
+
+    /// session of CLIENT_ADDR with `peer_addr`, shared by the tests of every session consumer
+    pub(crate) fn test_session(
+        peer_addr: &str,
+        rate_per_kb: u64,
+        rate_per_second: u64,
+    ) -> EphemeralSession {
+        EphemeralSession::new(
+            "client_id".to_owned(),
+            CLIENT_ADDR.to_owned(),
+            peer_addr.to_owned(),
+            rate_per_kb,
+            rate_per_second,
+            "login_session_id".to_owned(),
+        )
+    }
+
+    fn session(duration: i64, bandwidth_usage: u64) -> EphemeralSession {
+        let mut session = test_session(PEER_ADDR, 3, 2);
+        session.end_at = session.handshaked_at + duration;
+        session.bandwidth_usage = bandwidth_usage;
+        session
+    }
+
+    #[test]
+    fn test_try_new() {
+        let session = |client_addr: &str, peer_addr: &str| {
+            EphemeralSession::try_new(
+                "client_id".to_owned(),
+                client_addr.to_owned(),
+                peer_addr.to_owned(),
+                1,
+                1,
+                "login_session_id".to_owned(),
+            )
+        };
+        let normalized = session(&CLIENT_ADDR.to_lowercase(), &PEER_ADDR.to_lowercase()).unwrap();
+        assert_eq!(normalized.client_addr, CLIENT_ADDR);
+        assert_eq!(normalized.peer_addr, PEER_ADDR);
+        assert!(session("0xclient", PEER_ADDR).is_err());
+        assert!(session(CLIENT_ADDR, "0xpeer").is_err());
+
+        // `new` keeps invalid addresses as is
+        assert_eq!(test_session("0xpeer", 1, 1).peer_addr, "0xpeer");
+    }
+
+    #[test]
+    fn test_with_partner_discount() {
+        let price = UserBandwidthPrice::new(PEER_ADDR.to_lowercase(), 1_001, 3).unwrap();
+        let adj = |discount_bps: u16| PartnerRateAdjustment {
+            partner_id: "partner".to_owned(),
+            discount_bps,
+        };
+
+        assert_eq!(price.user_addr, PEER_ADDR);
+        assert!(UserBandwidthPrice::new("0xpeer".to_owned(), 1_001, 3).is_err());
+
+        let discounted = price.with_partner_discount(&adj(1_000));
+        assert_eq!(discounted.user_addr, price.user_addr);
+        // 900.9 and 2.7 are rounded down, a small rate keeps its discount
+        assert_eq!(
+            (discounted.rate_per_kb, discounted.rate_per_second),
+            (900, 2)
+        );
+
+        let discounted = price.with_partner_discount(&adj(0));
+        assert_eq!(
+            (discounted.rate_per_kb, discounted.rate_per_second),
+            (1_001, 3)
+        );
+        let discounted = price.with_partner_discount(&adj(MAX_BPS + 1));
+        assert_eq!((discounted.rate_per_kb, discounted.rate_per_second), (0, 0));
+    }
+
+    #[test]
+    fn test_client_total_bandwidth() {
+        let client_session = |client_addr: &str, bandwidth_usage: u64| {
+            let mut session = session(10, bandwidth_usage);
+            session.client_addr = client_addr.to_owned();
+            session
+        };
+        let sessions = [
+            client_session(CLIENT_ADDR, 2048),
+            client_session(CLIENT_ADDR, 1024),
+            client_session(PEER_ADDR, 4096),
+        ];
+        let used = client_total_bandwidth(&sessions, &CLIENT_ADDR.to_lowercase()).unwrap();
+        assert_eq!(used, 3072);
+        assert!(client_total_bandwidth(&sessions, "0xclient").is_err());
+        assert!(!is_over_quota(used, &ClientQuota { daily_kb: 3 }));
+        assert!(is_over_quota(used, &ClientQuota { daily_kb: 2 }));
+    }
+
+    #[test]
+    fn test_check_clock() {
+        let session = session(10, 0);
+        let now = session.handshaked_at;
+        assert!(session.check_clock(now, 60).is_ok());
+        assert!(session.check_clock(now + 60, 60).is_ok());
+        // handshaked_at in the future
+        assert!(session.check_clock(now - 61, 60).is_err());
+        // handshaked_at in the past
+        assert!(session.check_clock(now + 61, 60).is_err());
+    }
+
+    #[test]
+    fn test_compute_fees_u256() {
+        let session = session(10, 4096);
+        assert_eq!(session.compute_fees(), (20, 12, 32));
+
+        let (duration_fee, bandwidth_fee, total_fee) = session.compute_fees_u256(18);
+        assert_eq!(duration_fee, U256::from(20) * U256::exp10(12));
+        assert_eq!(bandwidth_fee, U256::from(12) * U256::exp10(12));
+        assert_eq!(total_fee, U256::from(32) * U256::exp10(12));
+    }
+
+    #[test]
+    fn test_latest_prices() {
+        let addr = "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed";
+        let price = |user_addr: &str, rate: i64, updated_at: i64| UserBandwidthPrice {
+            user_addr: user_addr.to_owned(),
+            rate_per_kb: rate,
+            rate_per_second: rate,
+            updated_at,
+        };
+        let latest = latest_prices(vec![
+            price(&addr.to_lowercase(), 10, 100),
+            price(addr, 30, 300),
+            price(&addr.to_uppercase().replace("0X", "0x"), 20, 200),
+            price("0xother", 5, 50),
+        ]);
+        assert_eq!(latest.len(), 2);
+        assert_eq!(latest[addr].rate_per_kb, 30);
+        assert_eq!(latest["0xother"].rate_per_kb, 5);
+    }
+
+    #[test]
+    fn test_avg_price_over() {
+        let price = |rate: i64| UserBandwidthPrice {
+            user_addr: "0xpeer".to_owned(),
+            rate_per_kb: rate,
+            rate_per_second: rate * 2,
+            updated_at: 0,
+        };
+        let mut history = PriceHistory::new("0xpeer".to_owned());
+        history.push(100, price(10));
+        history.push(200, price(40));
+
+        // 50s at 10 then 50s at 40
+        let avg = history.avg_price_over(150, 250);
+        assert_eq!((avg.rate_per_kb, avg.rate_per_second), (25, 50));
+        // before first price
+        assert_eq!(history.avg_price_over(0, 50).rate_per_kb, 10);
+        assert_eq!(history.avg_price_over(300, 300).rate_per_kb, 40);
+    }
+
+    #[test]
+    fn test_max_concurrent_sessions() {
+        let session = |peer_addr: &str, start: i64, end: i64| {
+            let mut session = test_session(PEER_ADDR, 1, 1);
+            session.peer_addr = peer_addr.to_owned();
+            session.handshaked_at = start;
+            session.end_at = end;
+            session
+        };
+        let sessions = vec![
+            session("0xa", 0, 10),
+            session("0xa", 5, 15),
+            session("0xa", 8, 8),
+            session("0xa", 15, 20),
+            session("0xb", 0, 10),
+            session("0xb", 10, 20),
+        ];
+        let max = max_concurrent_sessions(&sessions);
+        assert_eq!(max["0xa"], 3);
+        assert_eq!(max["0xb"], 1);
+    }
+
+    #[test]
+    fn test_fee_split() {
+        let session = session(0, 10 * 1024 + 512);
+        let now = session.handshaked_at + 30;
+        assert_eq!(session.fee_split(now), (60, 30));
+        assert_eq!(session.fee_split(session.handshaked_at - 5), (0, 30));
+    }
+
+    fn settled_session(status: SessionStatus, end_at: Option<i64>, total_fee: U256) -> Session {
+        Session::new(
+            H256::zero(),
+            "client_id".to_owned(),
+            Address::zero(),
+            Address::zero(),
+            U256::zero(),
+            U256::zero(),
+            None,
+            end_at,
+            None,
+            None,
+            U256::zero(),
+            U256::zero(),
+            total_fee,
+            status,
+            None,
+            None,
+        )
+    }
+
+    #[test]
+    fn test_session_from_events() {
+        let created = SessionCreatedExtra {
+            masternode_id: "masternode".to_owned(),
+            session: test_session(PEER_ADDR, 3, 2),
+        };
+        let mut session = created.session.clone();
+        session.end_at = session.handshaked_at + 10;
+        session.bandwidth_usage = 4096;
+        let terminated = SessionTerminatedExtra {
+            masternode_id: "masternode".to_owned(),
+            session,
+            reason: SessionTerminationReason::PeerDisconnected,
+        };
+
+        let session = Session::from_events(&created, &terminated).unwrap();
+        assert_eq!(session.duration, Some(10));
+        assert_eq!(session.bandwidth_usage, Some(4096));
+        assert_eq!(session.rate_per_kb, U256::from(3) * U256::exp10(12));
+        assert_eq!(session.total_fee, U256::from(32) * U256::exp10(12));
+        assert!(matches!(session.status, SessionStatus::Finished));
+        assert_eq!(format!("{:?}", session.session_hash), created.session.hash);
+
+        let mut other = terminated.clone();
+        other.session.hash = format!("{:?}", H256::zero());
+        assert!(Session::from_events(&created, &other).is_err());
+    }
+
+    #[test]
+    fn test_commission() {
+        let mut session = settled_session(SessionStatus::Finished, None, U256::exp10(18));
+        assert_eq!(
+            session.commission(250).unwrap(),
+            U256::from(25) * U256::exp10(15)
+        );
+        assert!(session.commission(10_001).is_err());
+
+        session.total_fee = U256::MAX;
+        assert!(session.commission(250).is_err());
+    }
+
+    #[test]
+    fn test_fee_variance() {
+        let szabo = |v: u64| U256::from(v) * U256::exp10(12);
+        let billed = |total_fee: U256| {
+            let mut session = settled_session(SessionStatus::Finished, Some(10), total_fee);
+            session.rate_per_second = szabo(2);
+            session.rate_per_kb = szabo(3);
+            session.duration = Some(10);
+            session.bandwidth_usage = Some(4096);
+            session
+        };
+        // expected 10 * 2 + 4 * 3 = 32
+        assert_eq!(fee_variance(&billed(szabo(32))), Some(0));
+        assert_eq!(fee_variance(&billed(szabo(35))), Some(3));
+        assert_eq!(fee_variance(&billed(szabo(30))), Some(-2));
+        assert_eq!(
+            fee_variance(&settled_session(SessionStatus::Finished, None, szabo(1))),
+            None
+        );
+
+        let sessions = [billed(szabo(32)), billed(szabo(35)), billed(szabo(30))];
+        let outliers = sessions_with_variance(&sessions, 2);
+        assert_eq!(outliers.len(), 1);
+        assert_eq!(outliers[0].total_fee, szabo(35));
+    }
+
+    #[test]
+    fn test_session_transition() {
+        let mut session = settled_session(SessionStatus::Active, None, U256::zero());
+        session
+            .transition(
+                SessionStatus::Finished,
+                Some(SessionTerminationReason::PeerDisconnected),
+            )
+            .unwrap();
+        assert_eq!(session.status, SessionStatus::Finished);
+        assert!(matches!(
+            session.reason,
+            Some(SessionTerminationReason::PeerDisconnected)
+        ));
+        assert!(session.end_at.is_some());
+
+        assert!(session.transition(SessionStatus::Active, None).is_err());
+        assert_eq!(session.status, SessionStatus::Finished);
+    }
+
+    #[test]
+    fn test_sessions_to_csv() {
+        let mut first = settled_session(SessionStatus::Finished, Some(20), U256::from(7));
+        first.handshake_at = Some(10);
+        first.client_identifier = "id,with \"quote\"".to_owned();
+        first.reason = Some(SessionTerminationReason::ClientInactive);
+        let mut second = settled_session(SessionStatus::Active, None, U256::zero());
+        second.handshake_at = Some(30);
+
+        let csv = sessions_to_csv(&[second.clone(), first.clone()]);
+        assert_eq!(csv, sessions_to_csv(&[first, second]));
+
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], SESSIONS_CSV_HEADER);
+        let zero_hash = format!("{:?}", H256::zero());
+        let zero_addr = address_to_string(Address::zero());
+        assert_eq!(
+            lines[1],
+            format!(
+                "{},\"id,with \"\"quote\"\"\",{},{},0,0,10,20,,,0,0,7,Finished,ClientInactive,",
+                zero_hash, zero_addr, zero_addr
+            )
+        );
+        assert!(lines[2].ends_with(",30,,,,0,0,0,Active,,"));
+
+        assert_eq!(
+            csv_escape("=HYPERLINK(\"x\")"),
+            "\"'=HYPERLINK(\"\"x\"\")\""
+        );
+        assert_eq!(csv_escape("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(csv_escape("-1+2"), "'-1+2");
+        assert_eq!(csv_escape("a=b"), "a=b");
+    }
+
+    #[test]
+    fn test_split_session_by_period() {
+        let day = BillingPeriod::Day.secs();
+        // 2024-01-01 23:00 UTC to 2024-01-03 01:00 UTC
+        let start = 1_704_150_000;
+        let end = start + day + 7200;
+        let mut session =
+            settled_session(SessionStatus::Finished, Some(end), U256::from(1_000_003));
+        session.handshake_at = Some(start);
+        session.bandwidth_usage = Some(26_001);
+        session.duration_fee = U256::from(1_000_000);
+        session.bandwidth_fee = U256::from(3);
+
+        let slices = split_session_by_period(&session, BillingPeriod::Day);
+        assert_eq!(slices.len(), 3);
+        assert_eq!(slices[0].period_start, start - 82_800);
+        assert_eq!(
+            (slices[1].start_at, slices[1].end_at),
+            (start + 3600, start + 3600 + day)
+        );
+        assert_eq!(slices[2].period_start, start + 3600 + day);
+        // 1h, 24h and 1h of 26h
+        let fees: Vec<U256> = slices.iter().map(|s| s.duration_fee).collect();
+        assert_eq!(
+            fees,
+            vec![U256::from(38_461), U256::from(923_076), U256::from(38_463)]
+        );
+        let usage: Vec<i64> = slices.iter().map(|s| s.bandwidth_usage).collect();
+        assert_eq!(usage, vec![1_000, 24_000, 1_001]);
+        let sum =
+            |f: fn(&SessionSlice) -> U256| slices.iter().fold(U256::zero(), |acc, s| acc + f(s));
+        assert_eq!(sum(|s| s.bandwidth_fee), session.bandwidth_fee);
+        assert_eq!(sum(|s| s.total_fee), session.total_fee);
+    }
+
+    #[test]
+    fn test_find_stale_active_sessions() {
+        let now = 1_000;
+        let sessions = vec![
+            settled_session(SessionStatus::Active, Some(now - 100), U256::zero()),
+            settled_session(SessionStatus::Active, Some(now - 10), U256::zero()),
+            settled_session(SessionStatus::Active, None, U256::zero()),
+            settled_session(SessionStatus::Finished, Some(now - 100), U256::zero()),
+        ];
+        let stale = find_stale_active_sessions(&sessions, now, 60);
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale[0].end_at, Some(now - 100));
+    }
 }
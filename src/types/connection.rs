@@ -1,10 +1,18 @@
-use dpn_proto::proxy_acc::ProtoProxyAcc;
+use anyhow::{anyhow, Result};
+use dpn_proto::{peer_stats::ProtoPeerStats, proxy_acc::ProtoProxyAcc};
 use num_derive::FromPrimitive;
+use prost::Message;
 use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
 use utoipa::ToSchema;
 
 use crate::utils::{bytes_to_hex_string, hash::hash};
 
+use super::geo::Geo;
+
 pub const DEFAULT_IP_ROTATION_PERIOD: i64 = 300;
 pub const MAX_INACTIVE_TIME: i64 = 300; // 300 seconds
 
@@ -25,6 +33,39 @@ pub struct PeernodeInfo {
     pub country_geoname_id: u32,
 }
 
+impl PeernodeInfo {
+    /// check claimed country against the country resolved from peer ip
+    /// `tolerated` lists extra country geoname ids accepted for ips near a border
+    pub fn verify_country(&self, observed: &Geo, tolerated: &[u32]) -> Result<(), GeoMismatch> {
+        let observed_id = observed.country.as_ref().and_then(|c| c.geoname_id);
+        match observed_id {
+            Some(id) if id == self.country_geoname_id || tolerated.contains(&id) => Ok(()),
+            _ => Err(GeoMismatch {
+                claimed: self.country_geoname_id,
+                observed: observed_id,
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeoMismatch {
+    pub claimed: u32,
+    pub observed: Option<u32>,
+}
+
+impl std::fmt::Display for GeoMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "peer country mismatch claimed={} observed={:?}",
+            self.claimed, self.observed
+        )
+    }
+}
+
+impl std::error::Error for GeoMismatch {}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PeerStats {
     pub masternode_id: String,
@@ -36,6 +77,48 @@ pub struct PeerStats {
     pub login_session_id: String,
 }
 
+impl PeerStats {
+    pub fn to_vec(&self) -> Vec<u8> {
+        let proto: ProtoPeerStats = self.clone().into();
+        let binding = ::prost::Message::encode_to_vec(&proto);
+        binding.as_slice().to_owned()
+    }
+
+    pub fn from_bytes(bz: &[u8]) -> Result<Self> {
+        let proto = ProtoPeerStats::decode(bz)
+            .map_err(|e| anyhow!("decode proto peer stats failed err={}", e))?;
+        Ok(proto.into())
+    }
+}
+
+impl From<PeerStats> for ProtoPeerStats {
+    fn from(stats: PeerStats) -> Self {
+        ProtoPeerStats {
+            masternode_id: stats.masternode_id,
+            session_hash: stats.session_hash,
+            download: stats.download,
+            upload: stats.upload,
+            c_download: stats.c_download,
+            c_upload: stats.c_upload,
+            login_session_id: stats.login_session_id,
+        }
+    }
+}
+
+impl From<ProtoPeerStats> for PeerStats {
+    fn from(proto: ProtoPeerStats) -> Self {
+        PeerStats {
+            masternode_id: proto.masternode_id,
+            session_hash: proto.session_hash,
+            download: proto.download,
+            upload: proto.upload,
+            c_download: proto.c_download,
+            c_upload: proto.c_upload,
+            login_session_id: proto.login_session_id,
+        }
+    }
+}
+
 #[derive(Debug, Clone, FromPrimitive, Serialize, Deserialize, ToSchema)]
 pub enum PrioritizedIPLevel {
     /// Replacable by other IPs if prioritized IP is unavailable
@@ -96,6 +179,44 @@ impl ProxyAccData {
         _self.id = bytes_to_hex_string(hash(bz).as_bytes());
         _self
     }
+
+    /// account whose ip rotation is due the soonest and when it is due
+    /// accounts with non-positive rotation period never rotate,
+    /// accounts missing from `last_rotations` are due now
+    pub fn next_rotation(
+        accs: &[ProxyAccData],
+        last_rotations: &HashMap<String, Instant>,
+    ) -> Option<(String, Instant)> {
+        let now = Instant::now();
+        accs.iter()
+            .filter(|acc| acc.ip_rotation_period > 0)
+            .map(|acc| {
+                let due_at = match last_rotations.get(&acc.id) {
+                    Some(last) => *last + Duration::from_secs(acc.ip_rotation_period as u64),
+                    None => now,
+                };
+                (acc.id.clone(), due_at)
+            })
+            .min_by_key(|(_, due_at)| *due_at)
+    }
+}
+
+/// ids of accounts without activity in the last `idle_for` secs, in order of `accs`
+/// `last_seen` is the last activity (unix secs) by account id, accounts never seen
+/// count from their `created_at` so new accounts are not reported right away
+pub fn find_idle_proxy_accs(
+    accs: &[ProxyAccData],
+    last_seen: &HashMap<String, i64>,
+    now: i64,
+    idle_for: i64,
+) -> Vec<String> {
+    accs.iter()
+        .filter(|acc| {
+            let last = last_seen.get(&acc.id).copied().unwrap_or(acc.created_at);
+            last.saturating_add(idle_for) <= now
+        })
+        .map(|acc| acc.id.clone())
+        .collect()
 }
 
 impl Into<ProtoProxyAcc> for ProxyAccData {
@@ -114,3 +235,118 @@ pub enum VerifyProxyAccData {
     // username, password
     BasicAuth(String, String),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::geo::Country;
+
+    fn proxy_acc(id: &str, ip_rotation_period: i64) -> ProxyAccData {
+        let mut acc = ProxyAccData::new(
+            "password".to_owned(),
+            ip_rotation_period,
+            None,
+            "0xuser".to_owned(),
+            0,
+            None,
+            0,
+            0,
+            None,
+            None,
+            0,
+        );
+        acc.id = id.to_owned();
+        acc
+    }
+
+    #[test]
+    fn test_verify_country() {
+        let info = PeernodeInfo {
+            peer_id: "peer_id".to_owned(),
+            ip_addr: "1.2.3.4".to_owned(),
+            throughput: 0.0,
+            rate_per_kb: 0,
+            rate_per_second: 0,
+            city_geoname_id: 0,
+            country_geoname_id: 1562822,
+        };
+        let geo = |geoname_id: Option<u32>| Geo {
+            country: geoname_id.map(|geoname_id| Country {
+                geoname_id: Some(geoname_id),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        assert!(info.verify_country(&geo(Some(1562822)), &[]).is_ok());
+        // ip near the border resolves to a neighbour country
+        assert!(info.verify_country(&geo(Some(1814991)), &[1814991]).is_ok());
+        assert_eq!(
+            info.verify_country(&geo(Some(1814991)), &[]),
+            Err(GeoMismatch {
+                claimed: 1562822,
+                observed: Some(1814991),
+            })
+        );
+        assert_eq!(
+            info.verify_country(&geo(None), &[1814991])
+                .unwrap_err()
+                .observed,
+            None
+        );
+    }
+
+    #[test]
+    fn test_next_rotation() {
+        let accs = vec![proxy_acc("a", 300), proxy_acc("b", 60), proxy_acc("c", 0)];
+        let base = Instant::now();
+        let last_rotations: HashMap<String, Instant> =
+            accs.iter().map(|acc| (acc.id.clone(), base)).collect();
+
+        let (id, due_at) = ProxyAccData::next_rotation(&accs, &last_rotations).unwrap();
+        assert_eq!(id, "b");
+        assert_eq!(due_at, base + Duration::from_secs(60));
+
+        assert!(ProxyAccData::next_rotation(&accs[2..], &last_rotations).is_none());
+    }
+
+    #[test]
+    fn test_find_idle_proxy_accs() {
+        let now = 10_000;
+        let mut accs = vec![proxy_acc("a", 0), proxy_acc("b", 0), proxy_acc("c", 0)];
+        accs[2].created_at = now - 10;
+        let last_seen = HashMap::from([("a".to_owned(), now - 3600), ("b".to_owned(), now - 60)]);
+
+        assert_eq!(
+            find_idle_proxy_accs(&accs, &last_seen, now, 600),
+            vec!["a".to_owned()]
+        );
+        assert_eq!(
+            find_idle_proxy_accs(&accs, &HashMap::new(), now, 600),
+            vec!["a".to_owned(), "b".to_owned()]
+        );
+    }
+
+    #[test]
+    fn test_peer_stats_proto_roundtrip() {
+        let stats = PeerStats {
+            masternode_id: "ms1".to_owned(),
+            session_hash: "97979e98f99f0ba2fb61b5cf00f55c0f33d294f549c46b2cb6599d60ccd5d9dd"
+                .to_owned(),
+            download: 123_456,
+            upload: 7_890,
+            c_download: 12,
+            c_upload: 34,
+            login_session_id: "login_session_id".to_owned(),
+        };
+        let bz = stats.to_vec();
+        assert!(bz.len() < serde_json::to_vec(&stats).unwrap().len());
+
+        let decoded = PeerStats::from_bytes(&bz).unwrap();
+        assert_eq!(decoded.session_hash, stats.session_hash);
+        assert_eq!(decoded.download, stats.download);
+        assert_eq!(decoded.c_upload, stats.c_upload);
+        // truncated frame of a flaky peer
+        assert!(PeerStats::from_bytes(&bz[..bz.len() - 1]).is_err());
+    }
+}
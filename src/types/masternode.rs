@@ -1,7 +1,18 @@
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
 use utoipa::ToSchema;
 
-use super::geo::Geo;
+use crate::utils::normalize_address;
+
+use super::{bandwidth::EphemeralSession, geo::Geo};
 
 pub const PEER_V0: [u8; 2] = [0u8, 0u8];
 pub const PEER_V1: [u8; 2] = [0u8, 1u8];
@@ -14,6 +25,80 @@ pub struct MasternodeInfo {
     pub web_bind: String,
     pub root_ca: Option<String>,
     pub geo: Geo,
+    /// max concurrent sessions, None means unlimited
+    pub max_sessions: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BindField {
+    Peer,
+    Client,
+    Control,
+    Web,
+}
+
+impl MasternodeInfo {
+    pub fn bind(&self, field: BindField) -> &str {
+        match field {
+            BindField::Peer => &self.peer_bind,
+            BindField::Client => &self.client_bind,
+            BindField::Control => &self.control_bind,
+            BindField::Web => &self.web_bind,
+        }
+    }
+
+    /// parse a bind field, IPv6 hosts must be bracketed e.g. `[::1]:9000`
+    pub fn bind_addr(&self, field: BindField) -> Result<SocketAddr> {
+        parse_bind(self.bind(field))
+    }
+
+    /// whether the bind field is a valid IPv6 socket address
+    pub fn is_ipv6_bind(&self, field: BindField) -> bool {
+        self.bind_addr(field).is_ok_and(|addr| addr.is_ipv6())
+    }
+
+    /// configured region, the continent code of its geo
+    pub fn region(&self) -> Option<&str> {
+        self.geo.continent.as_ref()?.code.as_deref()
+    }
+}
+
+/// most common region among distinct peers of `peers`, ties go to the smallest region
+/// masternode geo is configured statically, comparing it with `MasternodeInfo::region`
+/// catches nodes deployed in the wrong place
+/// `region_map` maps peer_addr to region (continent code), unknown peers are skipped
+/// addresses are compared in their normalized form, invalid ones match no peer
+pub fn dominant_region(
+    peers: &[EphemeralSession],
+    region_map: &HashMap<String, String>,
+) -> Option<String> {
+    let region_map: HashMap<String, &String> = region_map
+        .iter()
+        .filter_map(|(addr, region)| Some((normalize_address(addr).ok()?, region)))
+        .collect();
+    let peer_addrs: HashSet<String> = peers
+        .iter()
+        .filter_map(|s| normalize_address(&s.peer_addr).ok())
+        .collect();
+    let mut counts: HashMap<&String, usize> = HashMap::new();
+    for region in peer_addrs
+        .iter()
+        .filter_map(|addr| region_map.get(addr).copied())
+    {
+        *counts.entry(region).or_default() += 1;
+    }
+    counts
+        .into_iter()
+        .max_by(|(a, a_count), (b, b_count)| a_count.cmp(b_count).then_with(|| b.cmp(a)))
+        .map(|(region, _)| region.clone())
+}
+
+/// parse and canonicalize a `host:port` bind string
+/// e.g. `[0:0::1]:9000` is rendered back as `[::1]:9000`
+pub fn parse_bind(bind: &str) -> Result<SocketAddr> {
+    bind.trim()
+        .parse::<SocketAddr>()
+        .map_err(|e| anyhow!("invalid bind address bind={} err={}", bind, e))
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
@@ -26,3 +111,156 @@ pub struct ActivePeersClients {
     pub active_peers: u32,
     pub active_clients: u32,
 }
+
+/// admission control of new sessions against `MasternodeInfo::max_sessions`
+/// the slot taken by `try_admit` is freed when the returned guard is dropped
+#[derive(Debug, Clone)]
+pub struct AdmissionController {
+    max_sessions: Option<u32>,
+    active_sessions: Arc<AtomicU32>,
+}
+
+#[derive(Debug)]
+pub struct AdmitGuard {
+    active_sessions: Arc<AtomicU32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rejected {
+    pub active_sessions: u32,
+    pub max_sessions: u32,
+}
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "masternode overloaded active_sessions={} max_sessions={}",
+            self.active_sessions, self.max_sessions
+        )
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+impl AdmissionController {
+    pub fn new(info: &MasternodeInfo) -> Self {
+        Self {
+            max_sessions: info.max_sessions,
+            active_sessions: Arc::new(AtomicU32::new(0)),
+        }
+    }
+
+    pub fn active_sessions(&self) -> u32 {
+        self.active_sessions.load(Ordering::Acquire)
+    }
+
+    pub fn try_admit(&self) -> Result<AdmitGuard, Rejected> {
+        let max_sessions = self.max_sessions.unwrap_or(u32::MAX);
+        self.active_sessions
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                (active < max_sessions).then_some(active + 1)
+            })
+            .map(|_| AdmitGuard {
+                active_sessions: self.active_sessions.clone(),
+            })
+            .map_err(|active_sessions| Rejected {
+                active_sessions,
+                max_sessions,
+            })
+    }
+}
+
+impl Drop for AdmitGuard {
+    fn drop(&mut self) {
+        self.active_sessions.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::bandwidth::tests::test_session;
+
+    fn masternode_info(peer_bind: &str, control_bind: &str) -> MasternodeInfo {
+        MasternodeInfo {
+            peer_bind: peer_bind.to_owned(),
+            client_bind: "".to_owned(),
+            control_bind: control_bind.to_owned(),
+            web_bind: "".to_owned(),
+            root_ca: None,
+            geo: Geo {
+                city: None,
+                continent: None,
+                country: None,
+                location: None,
+            },
+            max_sessions: Some(2),
+        }
+    }
+
+    #[test]
+    fn test_bind_addr() {
+        let info = masternode_info("[::1]:9000", "0.0.0.0:9000");
+        assert!(info.is_ipv6_bind(BindField::Peer));
+        assert!(!info.is_ipv6_bind(BindField::Control));
+        assert!(!info.is_ipv6_bind(BindField::Web));
+
+        let peer = info.bind_addr(BindField::Peer).unwrap();
+        assert_eq!(peer.port(), 9000);
+        assert_eq!(peer.to_string(), "[::1]:9000");
+        assert_eq!(
+            info.bind_addr(BindField::Control).unwrap().to_string(),
+            "0.0.0.0:9000"
+        );
+        assert_eq!(
+            parse_bind("[0:0::1]:9000").unwrap().to_string(),
+            "[::1]:9000"
+        );
+        assert!(parse_bind("::1:9000").is_err());
+    }
+
+    #[test]
+    fn test_dominant_region() {
+        let session = |peer_addr: &str| test_session(peer_addr, 1, 1);
+        let a = "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359";
+        let b = "0xdbF03B407c01E7cD3CBea99509d93f8DDDC8C6FB";
+        let c = "0x0000000000000000000000000000000000000c0c";
+        let d = "0x0000000000000000000000000000000000000d0d";
+        // region map keys in any casing match the checksummed session addresses
+        let region_map: HashMap<String, String> = [
+            (a.to_lowercase(), "AS"),
+            (b.to_owned(), "EU"),
+            (c.to_owned(), "EU"),
+        ]
+        .into_iter()
+        .map(|(addr, region)| (addr, region.to_owned()))
+        .collect();
+
+        // peers are counted once however many sessions they serve
+        let peers = [session(a), session(a), session(a), session(b)];
+        assert_eq!(dominant_region(&peers, &region_map), Some("AS".to_owned()));
+        let peers = [session(a), session(b), session(c), session(d)];
+        assert_eq!(dominant_region(&peers, &region_map), Some("EU".to_owned()));
+        assert_eq!(dominant_region(&[session(d)], &region_map), None);
+    }
+
+    #[test]
+    fn test_admission_controller() {
+        let info = masternode_info("", "");
+        let controller = AdmissionController::new(&info);
+        let first = controller.try_admit().unwrap();
+        let _second = controller.try_admit().unwrap();
+        assert_eq!(
+            controller.try_admit().unwrap_err(),
+            Rejected {
+                active_sessions: 2,
+                max_sessions: 2
+            }
+        );
+
+        drop(first);
+        assert_eq!(controller.active_sessions(), 1);
+        assert!(controller.try_admit().is_ok());
+    }
+}
@@ -12,7 +12,9 @@ pub mod noti;
 pub mod referral;
 pub mod region;
 pub mod reward;
+pub mod schema;
 pub mod sg_meta;
+pub mod stats;
 pub mod stream_payload;
 pub mod tier;
 pub mod tx;
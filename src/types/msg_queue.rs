@@ -1,10 +1,15 @@
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::utils::{gunzip, gzip_above, peer_identity};
 
 use super::{
     bandwidth::{EphemeralSession, SessionTerminationReason},
     connection::PeernodeInfo,
     internal_tx::InternalTx,
     noti::NotificationRegister,
+    tier::Tier,
     tx::{Tx, TxStatus},
 };
 // exchanges
@@ -38,6 +43,7 @@ pub const SESSION_ROUTING_KEY: &str = "session";
 pub const TXS_ROUTING_KEY: &str = "txs";
 pub const TAPPOINT_EVENT_ROUTING_KEY: &str = "tappoint";
 pub const NOTIFICATION_REGISTER_ROUTING_KEY: &str = "register";
+pub const TIER_ROUTING_KEY: &str = "tier";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 
@@ -54,6 +60,189 @@ pub enum DPNEvent {
     Deposit(DepositExtra),
     Withdrawal(WithdrawalExtra),
     Referral(ReferralExtra),
+
+    // tier
+    TierChanged(TierChangedExtra),
+}
+
+#[cfg(feature = "avro")]
+impl DPNEvent {
+    /// encode event as avro datum using the committed schema in `avro/dpn_event.avsc`
+    /// avro resolves the `value` union to the first record it fits and ignores extra fields,
+    /// so in the schema an extra must be listed before any extra whose fields are its subset
+    pub fn to_avro(&self) -> anyhow::Result<Vec<u8>> {
+        let schema = dpn_event_avro_schema()?;
+        let value = apache_avro::to_value(self)
+            .and_then(|v| v.resolve(schema))
+            .map_err(|e| anyhow::anyhow!("avro encode dpn event failed err={}", e))?;
+        apache_avro::to_avro_datum(schema, value)
+            .map_err(|e| anyhow::anyhow!("avro encode dpn event failed err={}", e))
+    }
+
+    pub fn from_avro(bz: &[u8]) -> anyhow::Result<DPNEvent> {
+        let schema = dpn_event_avro_schema()?;
+        let mut reader = bz;
+        let value = apache_avro::from_avro_datum(schema, &mut reader, None)
+            .map_err(|e| anyhow::anyhow!("avro decode dpn event failed err={}", e))?;
+        apache_avro::from_value::<DPNEvent>(&value)
+            .map_err(|e| anyhow::anyhow!("avro decode dpn event failed err={}", e))
+    }
+}
+
+#[cfg(feature = "avro")]
+fn dpn_event_avro_schema() -> anyhow::Result<&'static apache_avro::Schema> {
+    static SCHEMA: std::sync::OnceLock<apache_avro::Schema> = std::sync::OnceLock::new();
+    if let Some(schema) = SCHEMA.get() {
+        return Ok(schema);
+    }
+    let schema = apache_avro::Schema::parse_str(include_str!("avro/dpn_event.avsc"))
+        .map_err(|e| anyhow::anyhow!("parse dpn event avro schema failed err={}", e))?;
+    Ok(SCHEMA.get_or_init(|| schema))
+}
+
+/// event violating an invariant of its variant, see `DPNEvent::validate`
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventValidationError {
+    pub event: &'static str,
+    pub reason: String,
+}
+
+impl std::fmt::Display for EventValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid dpn event event={} reason={}",
+            self.event, self.reason
+        )
+    }
+}
+
+impl std::error::Error for EventValidationError {}
+
+impl DPNEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DPNEvent::PeerConnected(_) => "PeerConnected",
+            DPNEvent::PeerDisconnected(_) => "PeerDisconnected",
+            DPNEvent::SessionCreated(_) => "SessionCreated",
+            DPNEvent::SessionTerminated(_) => "SessionTerminated",
+            DPNEvent::Deposit(_) => "Deposit",
+            DPNEvent::Withdrawal(_) => "Withdrawal",
+            DPNEvent::Referral(_) => "Referral",
+            DPNEvent::TierChanged(_) => "TierChanged",
+        }
+    }
+
+    /// routing key of the event on `EVENTS_EXCHANGE`
+    pub fn routing_key(&self) -> &'static str {
+        match self {
+            DPNEvent::PeerConnected(_) | DPNEvent::PeerDisconnected(_) => CONNECTION_ROUTING_KEY,
+            DPNEvent::SessionCreated(_) | DPNEvent::SessionTerminated(_) => SESSION_ROUTING_KEY,
+            DPNEvent::Deposit(_) => DEPOSIT_ROUTING_KEY,
+            DPNEvent::Withdrawal(_) => WITHDRAWAL_ROUTING_KEY,
+            DPNEvent::Referral(_) => REFERRAL_ROUTING_KEY,
+            DPNEvent::TierChanged(_) => TIER_ROUTING_KEY,
+        }
+    }
+
+    /// check invariants of the event so consumers only ever see well-formed events,
+    /// it is called by `to_queue_bytes` and the redis session publish path
+    pub fn validate(&self) -> Result<(), EventValidationError> {
+        let err = |reason: String| EventValidationError {
+            event: self.name(),
+            reason,
+        };
+        let non_empty = |fields: &[(&str, &str)]| match fields.iter().find(|(_, v)| v.is_empty()) {
+            Some((name, _)) => Err(err(format!("empty {}", name))),
+            None => Ok(()),
+        };
+        let session = |masternode_id: &str, session: &EphemeralSession| {
+            non_empty(&[
+                ("masternode_id", masternode_id),
+                ("session.hash", &session.hash),
+                ("session.client_addr", &session.client_addr),
+                ("session.peer_addr", &session.peer_addr),
+            ])
+        };
+
+        match self {
+            DPNEvent::PeerConnected(e) => non_empty(&[
+                ("masternode_id", &e.masternode_id),
+                ("peer_addr", &e.peer_addr),
+                ("login_session_id", &e.login_session_id),
+            ]),
+            DPNEvent::PeerDisconnected(e) => non_empty(&[
+                ("masternode_id", &e.masternode_id),
+                ("peer_addr", &e.peer_addr),
+                ("login_session_id", &e.login_session_id),
+            ]),
+            DPNEvent::SessionCreated(e) => session(&e.masternode_id, &e.session),
+            DPNEvent::SessionTerminated(e) => {
+                session(&e.masternode_id, &e.session)?;
+                if e.session.end_at < e.session.handshaked_at {
+                    return Err(err(format!(
+                        "end_at={} before handshaked_at={}",
+                        e.session.end_at, e.session.handshaked_at
+                    )));
+                }
+                Ok(())
+            }
+            DPNEvent::Deposit(e) => {
+                non_empty(&[("from", &e.from), ("to", &e.to), ("tx_hash", &e.tx_hash)])?;
+                if e.amount == 0 {
+                    return Err(err("zero amount".to_owned()));
+                }
+                Ok(())
+            }
+            DPNEvent::Withdrawal(e) => non_empty(&[
+                ("user_addr", &e.user_addr),
+                ("withdrawal_addr", &e.withdrawal_addr),
+            ]),
+            DPNEvent::Referral(e) => {
+                non_empty(&[
+                    ("referrer_addr", &e.referrer_addr),
+                    ("referee_addr", &e.referee_addr),
+                ])?;
+                if e.referrer_addr.eq_ignore_ascii_case(&e.referee_addr) {
+                    return Err(err(format!("self referral addr={}", e.referrer_addr)));
+                }
+                Ok(())
+            }
+            DPNEvent::TierChanged(e) => {
+                non_empty(&[("user_addr", &e.user_addr)])?;
+                if e.from == e.to {
+                    return Err(err(format!("tier unchanged tier={:?}", e.to)));
+                }
+                if e.points < 0 {
+                    return Err(err(format!("negative points={}", e.points)));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// default `compress_threshold` of `DPNEvent::to_queue_bytes` for the high volume session events
+pub const DEFAULT_EVENT_COMPRESS_THRESHOLD: usize = 1024;
+
+impl DPNEvent {
+    /// json bytes, gzipped when larger than `compress_threshold` bytes, None never compresses
+    /// small events stay plain json so existing consumers can read them as is
+    /// invalid events are rejected, see `validate`
+    pub fn to_queue_bytes(&self, compress_threshold: Option<usize>) -> Result<Vec<u8>> {
+        self.validate()?;
+        let bz =
+            serde_json::to_vec(self).map_err(|e| anyhow!("encode dpn event failed err={}", e))?;
+        gzip_above(bz, compress_threshold)
+            .map_err(|e| anyhow!("compress dpn event failed err={}", e))
+    }
+
+    /// decode bytes of `to_queue_bytes`, compressed or not
+    /// json never starts with GZIP_MAGIC, so plain events are told apart from compressed ones
+    pub fn from_queue_bytes(bz: &[u8]) -> Result<DPNEvent> {
+        let json = gunzip(bz).map_err(|e| anyhow!("inflate dpn event failed err={}", e))?;
+        serde_json::from_slice(&json).map_err(|e| anyhow!("decode dpn event failed err={}", e))
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -77,6 +266,20 @@ pub struct ClientBalanceUpdate {
     pub balance: i64,
 }
 
+/// keep only the latest update of each user, in order of each user's latest update
+pub fn coalesce_balance_updates(updates: Vec<ClientBalanceUpdate>) -> Vec<ClientBalanceUpdate> {
+    let mut latest: HashMap<String, usize> = HashMap::new();
+    for (i, update) in updates.iter().enumerate() {
+        latest.insert(update.user_addr.clone(), i);
+    }
+    updates
+        .into_iter()
+        .enumerate()
+        .filter(|(i, update)| latest.get(&update.user_addr) == Some(i))
+        .map(|(_, update)| update)
+        .collect()
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PeerConnectedExtra {
     pub masternode_id: String,
@@ -92,6 +295,18 @@ pub struct PeerDisconnectedExtra {
     pub login_session_id: String,
 }
 
+impl PeerConnectedExtra {
+    pub fn peer_identity(&self) -> String {
+        peer_identity(&self.peer_addr, &self.login_session_id)
+    }
+}
+
+impl PeerDisconnectedExtra {
+    pub fn peer_identity(&self) -> String {
+        peer_identity(&self.peer_addr, &self.login_session_id)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SessionCreatedExtra {
     pub masternode_id: String,
@@ -125,6 +340,14 @@ pub struct ReferralExtra {
     pub referee_addr: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TierChangedExtra {
+    pub user_addr: String,
+    pub from: Tier,
+    pub to: Tier,
+    pub points: i64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum DPNTx {
     Tx(Tx),
@@ -135,3 +358,201 @@ pub enum DPNTx {
 pub enum NotificationEvent {
     Register(NotificationRegister),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::bandwidth::tests::{test_session, PEER_ADDR};
+
+    #[test]
+    fn test_coalesce_balance_updates() {
+        let update = |user_addr: &str, balance: i64| ClientBalanceUpdate {
+            user_addr: user_addr.to_owned(),
+            balance,
+        };
+        let updates = coalesce_balance_updates(vec![
+            update("0xa", 100),
+            update("0xb", 50),
+            update("0xa", 90),
+            update("0xa", 80),
+        ]);
+        let updates: Vec<(String, i64)> = updates
+            .into_iter()
+            .map(|u| (u.user_addr, u.balance))
+            .collect();
+        assert_eq!(
+            updates,
+            vec![("0xb".to_owned(), 50), ("0xa".to_owned(), 80)]
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let deposit = |amount: u64| {
+            DPNEvent::Deposit(DepositExtra {
+                from: "0xfrom".to_owned(),
+                to: "0xto".to_owned(),
+                amount,
+                tx_hash: "0xtx".to_owned(),
+            })
+        };
+        assert!(deposit(1).validate().is_ok());
+        assert_eq!(deposit(0).validate().unwrap_err().reason, "zero amount");
+
+        let referral = |referrer_addr: &str, referee_addr: &str| {
+            DPNEvent::Referral(ReferralExtra {
+                referrer_addr: referrer_addr.to_owned(),
+                referee_addr: referee_addr.to_owned(),
+            })
+        };
+        assert!(referral("0xa", "0xb").validate().is_ok());
+        assert!(referral("0xAb", "0xab").validate().is_err());
+        let err = referral("0xa", "").validate().unwrap_err();
+        assert_eq!(
+            (err.event, err.reason.as_str()),
+            ("Referral", "empty referee_addr")
+        );
+        assert!(referral("0xa", "0xa").to_queue_bytes(None).is_err());
+    }
+
+    #[test]
+    fn test_queue_bytes_compression() {
+        let session = test_session(PEER_ADDR, 10, 20);
+        let event = DPNEvent::SessionTerminated(SessionTerminatedExtra {
+            masternode_id: "masternode".to_owned(),
+            session: session.clone(),
+            reason: SessionTerminationReason::ClientInactive,
+        });
+        let plain = event.to_queue_bytes(None).unwrap();
+        assert_eq!(plain, serde_json::to_vec(&event).unwrap());
+        assert_eq!(event.to_queue_bytes(Some(plain.len())).unwrap(), plain);
+
+        let compressed = event.to_queue_bytes(Some(plain.len() - 1)).unwrap();
+        assert!(compressed.starts_with(&crate::utils::GZIP_MAGIC));
+
+        for bz in [plain, compressed] {
+            match DPNEvent::from_queue_bytes(&bz).unwrap() {
+                DPNEvent::SessionTerminated(s) => {
+                    assert_eq!(s.session.hash, session.hash);
+                    assert!(matches!(s.reason, SessionTerminationReason::ClientInactive));
+                }
+                other => panic!("unexpected event {:?}", other),
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "avro"))]
+mod avro_tests {
+    use super::*;
+    use crate::types::bandwidth::tests::{test_session, PEER_ADDR};
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_avro_deposit_round_trip() {
+        let event = DPNEvent::Deposit(DepositExtra {
+            from: "0xfrom".to_owned(),
+            to: "0xto".to_owned(),
+            amount: 1_000,
+            tx_hash: "0xhash".to_owned(),
+        });
+        let bz = event.to_avro().unwrap();
+        match DPNEvent::from_avro(&bz).unwrap() {
+            DPNEvent::Deposit(d) => {
+                assert_eq!(d.from, "0xfrom");
+                assert_eq!(d.to, "0xto");
+                assert_eq!(d.amount, 1_000);
+                assert_eq!(d.tx_hash, "0xhash");
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_avro_session_created_round_trip() {
+        let session = test_session(PEER_ADDR, 10, 20);
+        let event = DPNEvent::SessionCreated(SessionCreatedExtra {
+            masternode_id: "masternode".to_owned(),
+            session: session.clone(),
+        });
+        let bz = event.to_avro().unwrap();
+        match DPNEvent::from_avro(&bz).unwrap() {
+            DPNEvent::SessionCreated(s) => {
+                assert_eq!(s.masternode_id, "masternode");
+                assert_eq!(s.session.hash, session.hash);
+                assert_eq!(s.session.rate_per_kb, 10);
+                assert_eq!(s.session.rate_per_second, 20);
+                assert_eq!(s.session.handshaked_at, session.handshaked_at);
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+
+    /// the schema is written by hand, every variant must survive a round trip field for field
+    #[test]
+    fn test_avro_round_trip_every_variant() {
+        let session = test_session(PEER_ADDR, 10, 20);
+        let events = vec![
+            DPNEvent::PeerConnected(PeerConnectedExtra {
+                masternode_id: "masternode".to_owned(),
+                peer_addr: "0xpeer".to_owned(),
+                login_session_id: "login_session_id".to_owned(),
+                info: PeernodeInfo {
+                    peer_id: "peer_id".to_owned(),
+                    ip_addr: "1.2.3.4".to_owned(),
+                    throughput: 12.5,
+                    rate_per_kb: 10,
+                    rate_per_second: 20,
+                    city_geoname_id: 1566083,
+                    country_geoname_id: 1562822,
+                },
+            }),
+            DPNEvent::PeerDisconnected(PeerDisconnectedExtra {
+                masternode_id: "masternode".to_owned(),
+                peer_addr: "0xpeer".to_owned(),
+                login_session_id: "login_session_id".to_owned(),
+            }),
+            DPNEvent::SessionCreated(SessionCreatedExtra {
+                masternode_id: "masternode".to_owned(),
+                session: session.clone(),
+            }),
+            DPNEvent::SessionTerminated(SessionTerminatedExtra {
+                masternode_id: "masternode".to_owned(),
+                session,
+                reason: SessionTerminationReason::PeerDisconnected,
+            }),
+            DPNEvent::Deposit(DepositExtra {
+                from: "0xfrom".to_owned(),
+                to: "0xto".to_owned(),
+                amount: 1_000,
+                tx_hash: "0xhash".to_owned(),
+            }),
+            DPNEvent::Withdrawal(WithdrawalExtra {
+                user_addr: "0xuser".to_owned(),
+                withdrawal_addr: "0xwithdrawal".to_owned(),
+            }),
+            DPNEvent::Referral(ReferralExtra {
+                referrer_addr: "0xreferrer".to_owned(),
+                referee_addr: "0xreferee".to_owned(),
+            }),
+            DPNEvent::TierChanged(TierChangedExtra {
+                user_addr: "0xuser".to_owned(),
+                from: Tier::Silver,
+                to: Tier::Gold,
+                points: 5_000,
+            }),
+        ];
+        let names: HashSet<&str> = events.iter().map(|e| e.name()).collect();
+        assert_eq!(names.len(), 8);
+
+        for event in events {
+            let decoded = DPNEvent::from_avro(&event.to_avro().unwrap()).unwrap();
+            assert_eq!(
+                serde_json::to_value(&decoded).unwrap(),
+                serde_json::to_value(&event).unwrap(),
+                "{} round trip",
+                event.name()
+            );
+        }
+    }
+}
@@ -1,7 +1,32 @@
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
+use web3::types::U256;
 
+use crate::utils::u256_to_szabo;
+
+use super::{
+    bandwidth::{EphemeralSession, PartnerRateAdjustment, MAX_BPS},
+    tier::{Tier, TierPoint, TierRewardMultipliers},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum RewardKind {
+    Network,
+    Task,
+    Referral,
+    Commission,
+}
+
+/// reward amount in szabo
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct RewardEntry {
+    pub kind: RewardKind,
+    pub amount: i64,
+    pub claimed: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, ToSchema)]
 pub struct RewardsOverview {
     /// claimed + unclaimed
     pub total_rewards: i64,
@@ -16,3 +41,323 @@ pub struct RewardsOverview {
     /// claimed + unclaimed
     pub total_commission_rewards: i64,
 }
+
+impl RewardsOverview {
+    /// overview derived from reward ledger so sub-totals always add up to totals
+    pub fn from_ledger(entries: &[RewardEntry]) -> RewardsOverview {
+        let mut overview = RewardsOverview::default();
+        for entry in entries {
+            let sub_total = match entry.kind {
+                RewardKind::Network => &mut overview.total_network_rewards,
+                RewardKind::Task => &mut overview.total_task_rewards,
+                RewardKind::Referral => &mut overview.total_referral_rewards,
+                RewardKind::Commission => &mut overview.total_commission_rewards,
+            };
+            *sub_total = sub_total.saturating_add(entry.amount);
+            overview.total_rewards = overview.total_rewards.saturating_add(entry.amount);
+            if !entry.claimed {
+                overview.unclaimed_rewards =
+                    overview.unclaimed_rewards.saturating_add(entry.amount);
+            }
+        }
+        overview
+    }
+
+    /// field-wise `self - prev`, e.g. rewards earned since last visit
+    /// a field that decreased after a data correction yields 0 instead of a negative delta
+    pub fn diff(&self, prev: &RewardsOverview) -> RewardsOverview {
+        let delta = |cur: i64, prev: i64| cur.saturating_sub(prev).max(0);
+        RewardsOverview {
+            total_rewards: delta(self.total_rewards, prev.total_rewards),
+            unclaimed_rewards: delta(self.unclaimed_rewards, prev.unclaimed_rewards),
+            total_network_rewards: delta(self.total_network_rewards, prev.total_network_rewards),
+            total_task_rewards: delta(self.total_task_rewards, prev.total_task_rewards),
+            total_referral_rewards: delta(self.total_referral_rewards, prev.total_referral_rewards),
+            total_commission_rewards: delta(
+                self.total_commission_rewards,
+                prev.total_commission_rewards,
+            ),
+        }
+    }
+
+    /// add a newly earned, not yet claimed commission (in wei) to the overview
+    pub fn add_commission(&mut self, commission: U256) {
+        let commission = u256_to_szabo(commission);
+        self.total_commission_rewards = self.total_commission_rewards.saturating_add(commission);
+        self.total_rewards = self.total_rewards.saturating_add(commission);
+        self.unclaimed_rewards = self.unclaimed_rewards.saturating_add(commission);
+    }
+
+    /// network + task rewards minus commission and referral cuts (szabo)
+    /// each cut is rounded toward zero on its own, the provider keeps the remainders
+    pub fn net_provider_payout(&self, commission_bps: u16, referral_bps: u16) -> Result<i64> {
+        let cuts_bps = commission_bps as u32 + referral_bps as u32;
+        if cuts_bps > MAX_BPS as u32 {
+            return Err(anyhow!(
+                "invalid payout cuts commission_bps={} referral_bps={}",
+                commission_bps,
+                referral_bps
+            ));
+        }
+        let gross = self
+            .total_network_rewards
+            .checked_add(self.total_task_rewards)
+            .ok_or(anyhow!(
+                "payout overflow network={} task={}",
+                self.total_network_rewards,
+                self.total_task_rewards
+            ))?;
+        let cut = |bps: u16| (gross as i128 * bps as i128 / MAX_BPS as i128) as i64;
+        // cuts sum to at most gross, so it cannot overflow
+        Ok(gross - cut(commission_bps) - cut(referral_bps))
+    }
+
+    /// add the commission part of a provider payout (in szabo), see `provider_payout`
+    /// returns the net amount paid to the provider
+    pub fn add_payout_commission(&mut self, gross: i64, commission_bps: u16) -> i64 {
+        let (net, commission) = provider_payout(gross, commission_bps);
+        self.total_commission_rewards = self.total_commission_rewards.saturating_add(commission);
+        self.total_rewards = self.total_rewards.saturating_add(commission);
+        self.unclaimed_rewards = self.unclaimed_rewards.saturating_add(commission);
+        net
+    }
+}
+
+/// split `gross` provider earnings into (net, commission), commission is rounded toward zero
+/// and net takes the remainder so `net + commission == gross` always holds
+/// `commission_bps` above MAX_BPS is capped
+pub fn provider_payout(gross: i64, commission_bps: u16) -> (i64, i64) {
+    let bps = commission_bps.min(MAX_BPS) as i128;
+    // |commission| <= |gross| so it fits back into i64
+    let commission = (gross as i128 * bps / MAX_BPS as i128) as i64;
+    (gross - commission, commission)
+}
+
+/// provider reward of a session after tier bonus, rounded down, saturates at u64::MAX
+pub fn session_reward(base_reward: u64, tier: &Tier, multipliers: &TierRewardMultipliers) -> u64 {
+    let bps = tier.reward_multiplier_bps(multipliers) as u128;
+    (base_reward as u128 * bps / MAX_BPS as u128).min(u64::MAX as u128) as u64
+}
+
+/// overview paying out tier points as task rewards, rounded down
+/// `point_to_reward_rate_bps` is szabo per point in bps, 10_000 pays 1 szabo per point
+/// totals saturate at i64::MAX
+pub fn from_tier_points(points: &[TierPoint], point_to_reward_rate_bps: u64) -> RewardsOverview {
+    let total_points = points
+        .iter()
+        .fold(0i64, |acc, p| acc.saturating_add(p.points))
+        .max(0);
+    let task_rewards = (total_points as u128 * point_to_reward_rate_bps as u128 / MAX_BPS as u128)
+        .min(i64::MAX as u128) as i64;
+    RewardsOverview {
+        total_rewards: task_rewards,
+        total_task_rewards: task_rewards,
+        ..Default::default()
+    }
+}
+
+/// adjustments applied to a session fee to get the provider reward, see `final_session_reward`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RewardContext {
+    pub tier: Tier,
+    pub multipliers: TierRewardMultipliers,
+    /// bonus of the peer country on top of the (discounted) fee
+    pub country_bonus_bps: u16,
+    pub partner: Option<PartnerRateAdjustment>,
+    pub commission_bps: u16,
+}
+
+/// components of a session reward in szabo, in the order they are applied
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RewardBreakdown {
+    pub base_fee: u64,
+    pub partner_discount: u64,
+    pub country_bonus: u64,
+    /// negative when the tier multiplier is below 1
+    pub tier_adjustment: i64,
+    pub commission: i64,
+    pub net_reward: i64,
+}
+
+/// provider reward of a session, adjustments are applied in this order:
+/// 1. base fee, `EphemeralSession::compute_fees` total
+/// 2. partner discount, discounted fee rounded down like `with_partner_discount`
+/// 3. country bonus on the discounted fee, rounded down
+/// 4. tier multiplier on the fee with bonus, see `session_reward`
+/// 5. commission taken from the result, see `provider_payout`
+pub fn final_session_reward(session: &EphemeralSession, ctx: &RewardContext) -> RewardBreakdown {
+    let max_bps = MAX_BPS as u128;
+    let (_, _, base_fee) = session.compute_fees();
+
+    let discount_bps = ctx
+        .partner
+        .as_ref()
+        .map_or(0, |p| p.discount_bps.min(MAX_BPS));
+    let kept = base_fee as u128 * (MAX_BPS - discount_bps) as u128;
+    let discounted = (kept / max_bps) as u64;
+
+    let country_bonus =
+        (discounted as u128 * ctx.country_bonus_bps as u128 / max_bps).min(u64::MAX as u128) as u64;
+    let with_bonus = discounted.saturating_add(country_bonus);
+
+    let gross = session_reward(with_bonus, &ctx.tier, &ctx.multipliers);
+    let tier_adjustment =
+        (gross as i128 - with_bonus as i128).clamp(i64::MIN as i128, i64::MAX as i128) as i64;
+
+    let (net_reward, commission) =
+        provider_payout(gross.min(i64::MAX as u64) as i64, ctx.commission_bps);
+    RewardBreakdown {
+        base_fee,
+        partner_discount: base_fee - discounted,
+        country_bonus,
+        tier_adjustment,
+        commission,
+        net_reward,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::bandwidth::tests::{test_session, PEER_ADDR};
+
+    #[test]
+    fn test_from_ledger() {
+        let entry = |kind: RewardKind, amount: i64, claimed: bool| RewardEntry {
+            kind,
+            amount,
+            claimed,
+        };
+        let overview = RewardsOverview::from_ledger(&[
+            entry(RewardKind::Network, 100, true),
+            entry(RewardKind::Network, 50, false),
+            entry(RewardKind::Task, 20, false),
+            entry(RewardKind::Referral, 5, true),
+            entry(RewardKind::Commission, 7, false),
+        ]);
+        assert_eq!(
+            overview,
+            RewardsOverview {
+                total_rewards: 182,
+                unclaimed_rewards: 77,
+                total_network_rewards: 150,
+                total_task_rewards: 20,
+                total_referral_rewards: 5,
+                total_commission_rewards: 7,
+            }
+        );
+    }
+
+    #[test]
+    fn test_from_tier_points() {
+        let point = |points: i64| TierPoint {
+            user_addr: "0xuser".to_owned(),
+            points,
+            created_at: 0,
+        };
+        let overview = from_tier_points(&[point(100), point(50), point(-25)], 25_000);
+        assert_eq!(
+            overview,
+            RewardsOverview {
+                total_rewards: 312,
+                total_task_rewards: 312,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_provider_payout() {
+        assert_eq!(provider_payout(1_001, 1_000), (901, 100));
+        assert_eq!(provider_payout(-1_001, 1_000), (-901, -100));
+        assert_eq!(provider_payout(i64::MAX, MAX_BPS + 1), (0, i64::MAX));
+        for gross in [0, 1, 9_999, 123_457] {
+            let (net, commission) = provider_payout(gross, 333);
+            assert_eq!(net + commission, gross);
+        }
+
+        let mut overview = RewardsOverview::default();
+        assert_eq!(overview.add_payout_commission(1_001, 1_000), 901);
+        assert_eq!(overview.total_commission_rewards, 100);
+        assert_eq!(overview.total_rewards, 100);
+    }
+
+    #[test]
+    fn test_net_provider_payout() {
+        let overview = RewardsOverview {
+            total_network_rewards: 10_001,
+            total_task_rewards: 2_000,
+            total_referral_rewards: 500,
+            ..Default::default()
+        };
+        // commission 300.025 -> 300, referral 600.05 -> 600
+        assert_eq!(overview.net_provider_payout(250, 500).unwrap(), 11_101);
+        assert_eq!(overview.net_provider_payout(0, 0).unwrap(), 12_001);
+        assert!(overview.net_provider_payout(5_000, 5_001).is_err());
+    }
+
+    #[test]
+    fn test_session_reward() {
+        let multipliers = TierRewardMultipliers {
+            gold_bps: 11_500,
+            ..Default::default()
+        };
+        // exact where 1.15 * 100.0 is 114.99999999999999
+        assert_eq!(session_reward(100, &Tier::Gold, &multipliers), 115);
+        assert_eq!(session_reward(7, &Tier::Silver, &multipliers), 7);
+        assert_eq!(
+            session_reward(u64::MAX, &Tier::Diamond, &multipliers),
+            u64::MAX
+        );
+    }
+
+    #[test]
+    fn test_final_session_reward() {
+        let mut session = test_session(PEER_ADDR, 3, 2);
+        session.end_at = session.handshaked_at + 100;
+        session.bandwidth_usage = 10 * 1024;
+        let ctx = RewardContext {
+            tier: Tier::Gold,
+            multipliers: TierRewardMultipliers::default(),
+            country_bonus_bps: 200,
+            partner: Some(PartnerRateAdjustment {
+                partner_id: "partner".to_owned(),
+                discount_bps: 1_500,
+            }),
+            commission_bps: 250,
+        };
+        // 230 -> 195 (195.5 rounded down) -> 198 -> 237 (x1.2) -> 232 after 5 commission
+        assert_eq!(
+            final_session_reward(&session, &ctx),
+            RewardBreakdown {
+                base_fee: 230,
+                partner_discount: 35,
+                country_bonus: 3,
+                tier_adjustment: 39,
+                commission: 5,
+                net_reward: 232,
+            }
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let prev = RewardsOverview {
+            total_rewards: 100,
+            unclaimed_rewards: 40,
+            total_network_rewards: 80,
+            total_task_rewards: 20,
+            ..Default::default()
+        };
+        let mut cur = prev.clone();
+        cur.add_commission(U256::from(5) * U256::exp10(12));
+        cur.total_task_rewards = 10;
+
+        let diff = cur.diff(&prev);
+        assert_eq!(diff.total_commission_rewards, 5);
+        assert_eq!(diff.total_rewards, 5);
+        assert_eq!(diff.unclaimed_rewards, 5);
+        assert_eq!(diff.total_network_rewards, 0);
+        assert_eq!(diff.total_task_rewards, 0);
+    }
+}
@@ -0,0 +1,48 @@
+use anyhow::{anyhow, Result};
+use utoipa::OpenApi;
+
+use super::{
+    bandwidth::{EphemeralSession, SessionStatus, SessionTerminationReason, UserBandwidthPrice},
+    reward::{RewardEntry, RewardKind, RewardsOverview},
+    stats::{GlobalStats, ProviderByCountryStats, StatsSnapshot},
+};
+
+/// types consumed by services in other languages
+/// every schema referenced by a listed type must be listed as well
+#[derive(OpenApi)]
+#[openapi(components(schemas(
+    EphemeralSession,
+    UserBandwidthPrice,
+    SessionStatus,
+    SessionTerminationReason,
+    RewardsOverview,
+    RewardEntry,
+    RewardKind,
+    StatsSnapshot,
+    ProviderByCountryStats,
+    GlobalStats,
+)))]
+struct DPNSchemas;
+
+/// OpenAPI components (JSON Schema) of the interop types as pretty json
+/// the committed snapshot is `schema/dpn_types.json`, regenerate it when a wire shape changes on purpose
+pub fn schema_export() -> Result<String> {
+    let components = DPNSchemas::openapi()
+        .components
+        .ok_or_else(|| anyhow!("schema export has no components"))?;
+    serde_json::to_string_pretty(&components).map_err(|e| anyhow!("schema export failed err={}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schema_export_is_stable() {
+        assert_eq!(
+            schema_export().unwrap().trim(),
+            include_str!("schema/dpn_types.json").trim(),
+            "wire shape changed, update src/types/schema/dpn_types.json if intended"
+        );
+    }
+}
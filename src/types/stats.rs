@@ -0,0 +1,122 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use utoipa::ToSchema;
+
+use super::bandwidth::EphemeralSession;
+use crate::utils::normalize_address;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct ProviderByCountryStats {
+    pub country_geoname_id: u32,
+    pub country_name: String,
+    pub active_providers: u32,
+    pub active_sessions: u32,
+    pub total_bandwidth: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct GlobalStats {
+    pub active_providers: u32,
+    pub active_clients: u32,
+    pub active_sessions: u32,
+    pub total_bandwidth: u64,
+}
+
+/// payload of STATS_WEBSOCKET_QUEUE / stats channel
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct StatsSnapshot {
+    pub at: i64,
+    pub by_country: Vec<ProviderByCountryStats>,
+    pub totals: GlobalStats,
+}
+
+/// provider addresses per country geoname id, drill-down of `ProviderByCountryStats`
+/// geoname ids are u32 like `ProviderByCountryStats::country_geoname_id` and the geoip lookups
+/// sessions do not carry provider country so it is looked up in `peer_countries` (peer_addr -> country geoname id),
+/// e.g. collected from PeerConnected events, sessions of unknown providers are skipped
+/// addresses are compared and returned in their normalized form, invalid ones match no country
+pub fn providers_by_country(
+    sessions: &[EphemeralSession],
+    peer_countries: &HashMap<String, u32>,
+) -> HashMap<u32, HashSet<String>> {
+    let peer_countries: HashMap<String, u32> = peer_countries
+        .iter()
+        .filter_map(|(addr, country)| Some((normalize_address(addr).ok()?, *country)))
+        .collect();
+    let mut by_country: HashMap<u32, HashSet<String>> = HashMap::new();
+    for peer_addr in sessions
+        .iter()
+        .filter_map(|s| normalize_address(&s.peer_addr).ok())
+    {
+        if let Some(country_geoname_id) = peer_countries.get(&peer_addr) {
+            by_country
+                .entry(*country_geoname_id)
+                .or_default()
+                .insert(peer_addr);
+        }
+    }
+    by_country
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::bandwidth::tests::{test_session, PEER_ADDR};
+
+    #[test]
+    fn test_serialize_deserialize() {
+        let snapshot = StatsSnapshot {
+            at: 1_700_000_000,
+            by_country: vec![ProviderByCountryStats {
+                country_geoname_id: 1562822,
+                country_name: "Vietnam".to_owned(),
+                active_providers: 3,
+                active_sessions: 5,
+                total_bandwidth: 1024,
+            }],
+            totals: GlobalStats {
+                active_providers: 3,
+                active_clients: 4,
+                active_sessions: 5,
+                total_bandwidth: 1024,
+            },
+        };
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert_eq!(
+            serde_json::from_str::<StatsSnapshot>(&json).unwrap(),
+            snapshot
+        );
+    }
+
+    #[test]
+    fn test_providers_by_country() {
+        let (a, b, c) = (
+            "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+            PEER_ADDR,
+            "0xdbF03B407c01E7cD3CBea99509d93f8DDDC8C6FB",
+        );
+        let session = |peer_addr: &str| {
+            let mut session = test_session(PEER_ADDR, 1, 1);
+            session.peer_addr = peer_addr.to_owned();
+            session
+        };
+        let sessions = vec![
+            session(a),
+            session(&a.to_lowercase()),
+            session(b),
+            session(c),
+            session("0xa"),
+        ];
+        // lower-case keys match checksummed session addresses
+        let peer_countries = HashMap::from([
+            (a.to_lowercase(), 1),
+            (b.to_owned(), 2),
+            ("0xa".to_owned(), 3),
+        ]);
+
+        let by_country = providers_by_country(&sessions, &peer_countries);
+        assert_eq!(by_country.len(), 2);
+        assert_eq!(by_country[&1], HashSet::from([a.to_owned()]));
+        assert_eq!(by_country[&2], HashSet::from([b.to_owned()]));
+    }
+}
@@ -1,9 +1,17 @@
+use anyhow::{anyhow, Result};
 use dpn_proto::stream_payload::{
-    proto_stream_payload::Payload, ProtoHealthCheck, ProtoProxyPayload, ProtoStreamPayload,
-    ProtoVpnPayload,
+    proto_stream_payload::Payload, ProtoHealthCheck, ProtoProxyPayload, ProtoStreamOrigin,
+    ProtoStreamPayload, ProtoVpnPayload,
 };
 use log::info;
-use prost::Message;
+use prost::{encoding, Message};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::utils::{hash::hash, truncate_str};
 
 #[derive(Debug, Clone)]
 pub enum StreamPayload {
@@ -12,6 +20,41 @@ pub enum StreamPayload {
     HealthCheck(HealthCheck),
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamPayloadError {
+    /// payload oneof is not set, e.g. sent by a newer/older peer
+    EmptyPayload,
+}
+
+impl std::fmt::Display for StreamPayloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StreamPayloadError::EmptyPayload => write!(f, "stream payload is empty"),
+        }
+    }
+}
+
+impl std::error::Error for StreamPayloadError {}
+
+impl StreamPayload {
+    /// checked conversion from proto, prefer it over the panicking `Into<StreamPayload>`
+    pub fn try_from(proto: ProtoStreamPayload) -> Result<StreamPayload, StreamPayloadError> {
+        let payload = match proto.payload.ok_or(StreamPayloadError::EmptyPayload)? {
+            Payload::ProxyPayload(p) => StreamPayload::ProxyPayload(ProxyPayload {
+                origin: StreamOrigin {
+                    origin_topic: p.origin_topic,
+                    stream_id: p.stream_id,
+                    duration: p.duration,
+                },
+                payload: p.payload,
+            }),
+            Payload::VpnPayload(_) => StreamPayload::VPNPayload(VPNPayload {}),
+            Payload::HealthCheck(_) => StreamPayload::HealthCheck(HealthCheck {}),
+        };
+        Ok(payload)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct VPNPayload {}
 
@@ -28,6 +71,211 @@ pub struct StreamOrigin {
     pub duration: u64,
 }
 
+impl StreamOrigin {
+    const TOPIC_LABEL_PREFIX: usize = 8;
+
+    /// bounded label of origin topic for logs and metric labels
+    /// first 8 chars sanitized to [A-Za-z0-9_] plus short hash of the rest
+    pub fn topic_label(&self) -> String {
+        let prefix: String = self
+            .origin_topic
+            .chars()
+            .take(Self::TOPIC_LABEL_PREFIX)
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let rest: String = self
+            .origin_topic
+            .chars()
+            .skip(Self::TOPIC_LABEL_PREFIX)
+            .collect();
+        if rest.is_empty() {
+            return prefix;
+        }
+        format!("{}_{}", prefix, hex::encode(&hash(rest.as_bytes())[..4]))
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let proto: ProtoStreamOrigin = self.clone().into();
+        ::prost::Message::encode_to_vec(&proto)
+    }
+
+    /// also accepts bytes of a `ProxyPayload`, the payload field is skipped
+    pub fn from_bytes(bz: &[u8]) -> Result<Self> {
+        let proto = ProtoStreamOrigin::decode(bz)
+            .map_err(|e| anyhow!("decode proto stream origin failed err={}", e))?;
+        Ok(proto.into())
+    }
+}
+
+impl std::fmt::Display for StreamOrigin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "origin_topic={} stream_id={} duration={}",
+            self.topic_label(),
+            self.stream_id,
+            self.duration
+        )
+    }
+}
+
+impl From<StreamOrigin> for ProtoStreamOrigin {
+    fn from(origin: StreamOrigin) -> Self {
+        ProtoStreamOrigin {
+            origin_topic: origin.origin_topic,
+            stream_id: origin.stream_id,
+            duration: origin.duration,
+        }
+    }
+}
+
+impl From<ProtoStreamOrigin> for StreamOrigin {
+    fn from(proto: ProtoStreamOrigin) -> Self {
+        StreamOrigin {
+            origin_topic: proto.origin_topic,
+            stream_id: proto.stream_id,
+            duration: proto.duration,
+        }
+    }
+}
+
+/// decides which frames `ProxyPayload::print_payload_sampled` logs
+/// a frame is logged when it is the 1st of every `every_n` frames
+/// or its len is at least `min_len`, 0 disables either rule
+#[derive(Debug)]
+pub struct PayloadLogSampler {
+    every_n: u64,
+    min_len: usize,
+    counter: AtomicU64,
+}
+
+impl PayloadLogSampler {
+    pub fn new(every_n: u64, min_len: usize) -> Self {
+        Self {
+            every_n,
+            min_len,
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    pub fn should_log(&self, len: usize) -> bool {
+        let n = self.counter.fetch_add(1, Ordering::Relaxed);
+        (self.every_n > 0 && n % self.every_n == 0)
+            || (self.min_len > 0 && len >= self.min_len)
+    }
+}
+
+impl Default for PayloadLogSampler {
+    /// logs every frame
+    fn default() -> Self {
+        Self::new(1, 0)
+    }
+}
+
+/// payload bytes a stream may send, `max_bytes_per_sec` for each second of its
+/// `StreamOrigin::duration` taken from the first frame of the stream, later frames cannot
+/// change it, a stream without duration cannot send any payload
+/// at most `max_streams` streams are tracked, close streams with `close`
+#[derive(Debug)]
+pub struct StreamBudget {
+    max_bytes_per_sec: u64,
+    max_streams: usize,
+    /// stream_tx_id -> usage
+    used: HashMap<String, StreamUsage>,
+}
+
+#[derive(Debug)]
+struct StreamUsage {
+    budget: u64,
+    used: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BudgetExceeded {
+    /// stream sent more than its budget
+    Bytes {
+        stream_tx_id: String,
+        used: u64,
+        budget: u64,
+    },
+    /// a new stream while `max_streams` streams are tracked
+    Streams {
+        stream_tx_id: String,
+        max_streams: usize,
+    },
+}
+
+impl std::fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BudgetExceeded::Bytes {
+                stream_tx_id,
+                used,
+                budget,
+            } => write!(
+                f,
+                "stream budget exceeded stream_tx_id={} used={} budget={}",
+                stream_tx_id, used, budget
+            ),
+            BudgetExceeded::Streams {
+                stream_tx_id,
+                max_streams,
+            } => write!(
+                f,
+                "stream budget exceeded, too many streams stream_tx_id={} max_streams={}",
+                stream_tx_id, max_streams
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BudgetExceeded {}
+
+impl StreamBudget {
+    pub fn new(max_bytes_per_sec: u64, max_streams: usize) -> Self {
+        Self {
+            max_bytes_per_sec,
+            max_streams,
+            used: HashMap::new(),
+        }
+    }
+
+    /// add payload bytes to its stream and return the bytes sent so far
+    /// the payload is not counted when it exceeds the budget
+    pub fn account(&mut self, payload: &ProxyPayload) -> Result<u64, BudgetExceeded> {
+        let stream_tx_id = payload.stream_tx_id();
+        if !self.used.contains_key(&stream_tx_id) && self.used.len() >= self.max_streams {
+            return Err(BudgetExceeded::Streams {
+                stream_tx_id,
+                max_streams: self.max_streams,
+            });
+        }
+        let budget = payload
+            .origin
+            .duration
+            .saturating_mul(self.max_bytes_per_sec);
+        let usage = self
+            .used
+            .entry(stream_tx_id.clone())
+            .or_insert(StreamUsage { budget, used: 0 });
+        let used = usage.used.saturating_add(payload.payload.len() as u64);
+        if used > usage.budget {
+            return Err(BudgetExceeded::Bytes {
+                stream_tx_id,
+                used,
+                budget: usage.budget,
+            });
+        }
+        usage.used = used;
+        Ok(used)
+    }
+
+    /// forget a closed stream
+    pub fn close(&mut self, stream_tx_id: &str) {
+        self.used.remove(stream_tx_id);
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ProxyPayload {
     pub origin: StreamOrigin,
@@ -45,28 +293,103 @@ impl ProxyPayload {
         binding.as_slice().to_owned()
     }
 
+    /// same bytes as `to_vec` written into `buf` (cleared first) without cloning the payload
+    /// field layout must follow ProtoProxyPayload
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.clear();
+        if !self.origin.origin_topic.is_empty() {
+            encoding::string::encode(1, &self.origin.origin_topic, buf);
+        }
+        if self.origin.stream_id != 0 {
+            encoding::uint64::encode(2, &self.origin.stream_id, buf);
+        }
+        if self.origin.duration != 0 {
+            encoding::uint64::encode(3, &self.origin.duration, buf);
+        }
+        if !self.payload.is_empty() {
+            encoding::bytes::encode(4, &self.payload, buf);
+        }
+    }
+
+    /// encode into a thread local buffer reused across frames and pass the bytes to `f`
+    pub fn encode_pooled<R>(&self, f: impl FnOnce(&[u8]) -> R) -> R {
+        thread_local! {
+            static BUF: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+        }
+        BUF.with(|buf| {
+            let mut buf = buf.borrow_mut();
+            self.encode_into(&mut buf);
+            f(&buf)
+        })
+    }
+
     pub fn from_bytes(bz: &[u8]) -> Self {
         let proto = ProtoProxyPayload::decode(bz).expect("decode proto stream payload failed");
         proto.into()
     }
 
+    /// iterate length-delimited payloads concatenated in `buf`
+    /// a frame that fails to decode yields an error and is skipped,
+    /// iteration ends after a malformed or truncated length prefix
+    pub fn decode_stream(buf: &[u8]) -> impl Iterator<Item = Result<ProxyPayload>> + '_ {
+        let mut rest = buf;
+        std::iter::from_fn(move || {
+            if rest.is_empty() {
+                return None;
+            }
+            let frame = prost::decode_length_delimiter(&mut rest)
+                .map_err(|e| anyhow!("decode proxy payload length failed err={}", e))
+                .and_then(|len| {
+                    if len > rest.len() {
+                        return Err(anyhow!(
+                            "proxy payload frame truncated len={} remaining={}",
+                            len,
+                            rest.len()
+                        ));
+                    }
+                    let (frame, tail) = rest.split_at(len);
+                    rest = tail;
+                    Ok(frame)
+                });
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(e) => {
+                    rest = &[];
+                    return Some(Err(e));
+                }
+            };
+            Some(
+                ProtoProxyPayload::decode(frame)
+                    .map(Into::into)
+                    .map_err(|e| anyhow!("decode proxy payload failed err={}", e)),
+            )
+        })
+    }
+
     pub fn print_payload(&self, outgoing: bool) {
         if outgoing {
             info!(
                 ">>>|out|>>> origin_topic={} stream_id={} len={}",
-                self.origin.origin_topic[0..10].to_string(),
+                truncate_str(&self.origin.origin_topic, 10),
                 self.origin.stream_id,
                 self.payload.len(),
             );
         } else {
             info!(
                 "<<<|inn|<<< origin_topic={} stream_id={} len={}",
-                self.origin.origin_topic[0..10].to_string(),
+                truncate_str(&self.origin.origin_topic, 10),
                 self.origin.stream_id,
                 self.payload.len(),
             );
         }
     }
+
+    /// same as `print_payload` but only for frames picked by `sampler`
+    pub fn print_payload_sampled(&self, outgoing: bool, sampler: &PayloadLogSampler) {
+        if sampler.should_log(self.payload.len()) {
+            self.print_payload(outgoing);
+        }
+    }
 }
 
 impl Into<ProtoProxyPayload> for ProxyPayload {
@@ -138,20 +461,11 @@ impl Into<ProtoStreamPayload> for StreamPayload {
     }
 }
 
+/// deprecated: panics on empty payload, use `StreamPayload::try_from`
+/// (`#[deprecated]` is rejected on trait impls)
 impl Into<StreamPayload> for ProtoStreamPayload {
     fn into(self) -> StreamPayload {
-        match self.payload.unwrap() {
-            Payload::ProxyPayload(p) => StreamPayload::ProxyPayload(ProxyPayload {
-                origin: StreamOrigin {
-                    origin_topic: p.origin_topic,
-                    stream_id: p.stream_id,
-                    duration: p.duration,
-                },
-                payload: p.payload,
-            }),
-            Payload::VpnPayload(_) => StreamPayload::VPNPayload(VPNPayload {}),
-            Payload::HealthCheck(_) => StreamPayload::HealthCheck(HealthCheck {}),
-        }
+        StreamPayload::try_from(self).expect("stream payload is empty")
     }
 }
 
@@ -181,4 +495,158 @@ mod tests {
         let payload = ProxyPayload::from_bytes(bz);
         let _ = payload.to_vec();
     }
+
+    #[test]
+    fn test_stream_budget() {
+        let payload = |stream_id: u64, duration: u64, len: usize| ProxyPayload {
+            origin: StreamOrigin {
+                origin_topic: "c_0x9797".to_owned(),
+                stream_id,
+                duration,
+            },
+            payload: vec![0u8; len],
+        };
+        // 2s at 100 bytes per sec
+        let mut budget = StreamBudget::new(100, 2);
+        assert_eq!(budget.account(&payload(1, 2, 150)), Ok(150));
+        assert_eq!(
+            budget.account(&payload(1, 2, 60)),
+            Err(BudgetExceeded::Bytes {
+                stream_tx_id: "c_0x9797:1".to_owned(),
+                used: 210,
+                budget: 200
+            })
+        );
+        // budget is the one of the first frame
+        assert!(budget.account(&payload(1, 10, 60)).is_err());
+        assert_eq!(budget.account(&payload(1, 2, 50)), Ok(200));
+        assert_eq!(
+            budget.account(&payload(2, 0, 1)),
+            Err(BudgetExceeded::Bytes {
+                stream_tx_id: "c_0x9797:2".to_owned(),
+                used: 1,
+                budget: 0
+            })
+        );
+        assert_eq!(
+            budget.account(&payload(3, 2, 1)),
+            Err(BudgetExceeded::Streams {
+                stream_tx_id: "c_0x9797:3".to_owned(),
+                max_streams: 2
+            })
+        );
+
+        budget.close("c_0x9797:1");
+        assert_eq!(budget.account(&payload(1, 2, 60)), Ok(60));
+    }
+
+    #[test]
+    fn test_encode_into() {
+        let payload = |topic: &str, stream_id: u64, payload: &[u8]| ProxyPayload {
+            origin: StreamOrigin {
+                origin_topic: topic.to_owned(),
+                stream_id,
+                duration: 30,
+            },
+            payload: payload.to_vec(),
+        };
+        let mut buf = b"stale".to_vec();
+        for p in [payload("c_0x9797", 4, b"CONNECT"), payload("", 0, b"")] {
+            p.encode_into(&mut buf);
+            assert_eq!(buf, p.to_vec());
+            assert_eq!(p.encode_pooled(|bz| bz.to_vec()), p.to_vec());
+        }
+    }
+
+    #[test]
+    fn test_stream_origin_round_trip() {
+        let origin = StreamOrigin {
+            origin_topic: "c_0x97979e98f99f0ba2fb61b5cf00f55c0f33d294f549c46b2cb6599d60ccd5d9dd"
+                .to_owned(),
+            stream_id: 4,
+            duration: 30,
+        };
+        let decoded = StreamOrigin::from_bytes(&origin.to_vec()).unwrap();
+        assert_eq!(decoded.origin_topic, origin.origin_topic);
+        assert_eq!(decoded.stream_id, 4);
+        assert_eq!(decoded.duration, 30);
+
+        let payload = ProxyPayload {
+            origin: origin.clone(),
+            payload: b"CONNECT".to_vec(),
+        };
+        let decoded = StreamOrigin::from_bytes(&payload.to_vec()).unwrap();
+        assert_eq!(decoded.to_string(), origin.to_string());
+        assert!(origin.to_string().starts_with("origin_topic=c_0x9797_"));
+
+        let bz = origin.to_vec();
+        assert!(StreamOrigin::from_bytes(&bz[..bz.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn test_decode_stream() {
+        let payload = |stream_id: u64, payload: &[u8]| ProtoProxyPayload {
+            origin_topic: "c_0x9797".to_owned(),
+            stream_id,
+            duration: 30,
+            payload: payload.to_vec(),
+        };
+        let mut buf = vec![];
+        payload(1, b"CONNECT")
+            .encode_length_delimited(&mut buf)
+            .unwrap();
+        // frame with an invalid wire type is reported and skipped
+        buf.extend_from_slice(&[1, 0x0f]);
+        payload(2, b"").encode_length_delimited(&mut buf).unwrap();
+        // truncated frame ends the stream
+        buf.extend_from_slice(&[10, 0x0a]);
+
+        let decoded: Vec<_> = ProxyPayload::decode_stream(&buf).collect();
+        assert_eq!(decoded.len(), 4);
+        let first = decoded[0].as_ref().unwrap();
+        assert_eq!(
+            (first.origin.stream_id, first.payload.as_slice()),
+            (1, &b"CONNECT"[..])
+        );
+        assert!(decoded[1].is_err());
+        assert_eq!(decoded[2].as_ref().unwrap().origin.stream_id, 2);
+        assert!(decoded[3].is_err());
+    }
+
+    #[test]
+    fn test_try_from_empty_payload() {
+        let bz = ProtoStreamPayload { payload: None }.encode_to_vec();
+        let proto = ProtoStreamPayload::decode(bz.as_slice()).unwrap();
+        assert_eq!(
+            StreamPayload::try_from(proto).unwrap_err(),
+            StreamPayloadError::EmptyPayload
+        );
+    }
+
+    #[test]
+    fn test_topic_label() {
+        let origin = |topic: &str| StreamOrigin {
+            origin_topic: topic.to_owned(),
+            stream_id: 0,
+            duration: 0,
+        };
+        assert_eq!(origin("c_0x12").topic_label(), "c_0x12");
+        let label = origin("c_0x97979e98f99f0ba2fb61b5cf00f55c0f33d294f5").topic_label();
+        assert!(label.starts_with("c_0x9797_"));
+        assert_eq!(label.len(), 8 + 1 + 8);
+        assert_eq!(origin("日本:語").topic_label(), "____");
+    }
+
+    #[test]
+    fn test_payload_log_sampler() {
+        let sampler = PayloadLogSampler::new(3, 1024);
+        let logged: Vec<bool> = [10, 10, 10, 10, 2048, 10]
+            .iter()
+            .map(|len| sampler.should_log(*len))
+            .collect();
+        assert_eq!(logged, vec![true, false, false, true, true, false]);
+
+        let sampler = PayloadLogSampler::new(0, 0);
+        assert!(!sampler.should_log(usize::MAX));
+    }
 }
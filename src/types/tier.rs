@@ -2,6 +2,8 @@ use num_derive::FromPrimitive;
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
 
+use super::msg_queue::{DPNEvent, TierChangedExtra};
+
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct UserTier {
     pub user_addr: String,
@@ -9,7 +11,29 @@ pub struct UserTier {
     pub points: i64,
 }
 
-#[derive(Debug, Clone, FromPrimitive, Serialize, Deserialize, ToSchema)]
+impl UserTier {
+    /// update points of user and the tier they fall into per `thresholds`
+    /// returns TierChanged event only when a threshold was crossed,
+    /// so recomputing within the same tier does not spam downstream consumers
+    pub fn recompute(&mut self, points: i64, thresholds: &TierThresholds) -> Option<DPNEvent> {
+        let from = self.tier.clone();
+        self.tier = Tier::from_points(points, thresholds);
+        self.points = points;
+
+        if from == self.tier {
+            return None;
+        }
+
+        Some(DPNEvent::TierChanged(TierChangedExtra {
+            user_addr: self.user_addr.clone(),
+            from,
+            to: self.tier.clone(),
+            points: self.points,
+        }))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, FromPrimitive, Serialize, Deserialize, ToSchema)]
 pub enum Tier {
     Bronze,
     Silver,
@@ -18,9 +42,119 @@ pub enum Tier {
     Diamond,
 }
 
+impl Tier {
+    /// highest tier whose threshold `points` reaches, Bronze below every threshold
+    pub fn from_points(points: i64, thresholds: &TierThresholds) -> Tier {
+        if points >= thresholds.diamond {
+            Tier::Diamond
+        } else if points >= thresholds.platinum {
+            Tier::Platinum
+        } else if points >= thresholds.gold {
+            Tier::Gold
+        } else if points >= thresholds.silver {
+            Tier::Silver
+        } else {
+            Tier::Bronze
+        }
+    }
+
+    /// multiplier of the tier in bps, see `TierRewardMultipliers`
+    pub fn reward_multiplier_bps(&self, multipliers: &TierRewardMultipliers) -> u32 {
+        match self {
+            Tier::Bronze => multipliers.bronze_bps,
+            Tier::Silver => multipliers.silver_bps,
+            Tier::Gold => multipliers.gold_bps,
+            Tier::Platinum => multipliers.platinum_bps,
+            Tier::Diamond => multipliers.diamond_bps,
+        }
+    }
+}
+
+/// provider reward multiplier per tier in bps (10_000 is x1), loaded from config
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct TierRewardMultipliers {
+    pub bronze_bps: u32,
+    pub silver_bps: u32,
+    pub gold_bps: u32,
+    pub platinum_bps: u32,
+    pub diamond_bps: u32,
+}
+
+impl Default for TierRewardMultipliers {
+    fn default() -> Self {
+        Self {
+            bronze_bps: 10_000,
+            silver_bps: 11_000,
+            gold_bps: 12_000,
+            platinum_bps: 13_500,
+            diamond_bps: 15_000,
+        }
+    }
+}
+
+/// min points of each tier, loaded from config
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct TierThresholds {
+    pub silver: i64,
+    pub gold: i64,
+    pub platinum: i64,
+    pub diamond: i64,
+}
+
+impl Default for TierThresholds {
+    fn default() -> Self {
+        Self {
+            silver: 1_000,
+            gold: 5_000,
+            platinum: 20_000,
+            diamond: 100_000,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct TierPoint {
     pub user_addr: String,
     pub points: i64,
     pub created_at: i64,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recompute_crosses_threshold() {
+        let thresholds = TierThresholds::default();
+        let mut user_tier = UserTier {
+            user_addr: "0xuser".to_owned(),
+            tier: Tier::Bronze,
+            points: 0,
+        };
+
+        assert!(user_tier.recompute(999, &thresholds).is_none());
+        assert_eq!(user_tier.tier, Tier::Bronze);
+
+        let event = user_tier.recompute(1_000, &thresholds);
+        let Some(DPNEvent::TierChanged(extra)) = event else {
+            panic!("unexpected event {:?}", event);
+        };
+        assert_eq!(
+            (extra.from, extra.to, extra.points),
+            (Tier::Bronze, Tier::Silver, 1_000)
+        );
+
+        // more points within the same tier, then a drop below the threshold
+        assert!(user_tier.recompute(4_999, &thresholds).is_none());
+        let event = user_tier.recompute(10, &thresholds);
+        assert!(matches!(event, Some(DPNEvent::TierChanged(e)) if e.to == Tier::Bronze));
+    }
+
+    #[test]
+    fn test_tier_from_points() {
+        let thresholds = TierThresholds::default();
+        assert_eq!(Tier::from_points(-5, &thresholds), Tier::Bronze);
+        assert_eq!(Tier::from_points(5_000, &thresholds), Tier::Gold);
+        assert_eq!(Tier::from_points(i64::MAX, &thresholds), Tier::Diamond);
+    }
+}
@@ -2,6 +2,9 @@ use serde::{Deserialize, Serialize};
 use web3::types::Address;
 use utoipa::ToSchema;
 
+/// max connected interval credited by a single accrual, longer intervals are clamped
+pub const MAX_UPTIME_XP_INTERVAL_SECS: u64 = 24 * 60 * 60;
+
 #[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 pub struct UserXp {
     pub user_addr: Address,
@@ -24,4 +27,30 @@ impl UserXp {
             updated_at,
         }
     }
-}
\ No newline at end of file
+}
+
+/// xp earned for `connected_secs` of uptime at `rate_per_hour`
+/// interval is clamped to MAX_UPTIME_XP_INTERVAL_SECS and negative rates earn nothing
+pub fn compute_uptime_xp(connected_secs: u64, rate_per_hour: i64) -> i64 {
+    if rate_per_hour <= 0 {
+        return 0;
+    }
+    let secs = connected_secs.min(MAX_UPTIME_XP_INTERVAL_SECS) as i64;
+    secs.saturating_mul(rate_per_hour) / 3600
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compute_uptime_xp() {
+        assert_eq!(compute_uptime_xp(2 * 3600, 10), 20);
+        assert_eq!(compute_uptime_xp(2 * 3600, -10), 0);
+    }
+
+    #[test]
+    fn test_compute_uptime_xp_clamped() {
+        assert_eq!(compute_uptime_xp(u64::MAX, 10), 240);
+    }
+}
@@ -1,7 +1,13 @@
 pub mod hash;
 
-use ethers::utils::{format_units, parse_units};
+use anyhow::{anyhow, Result};
+use ethers::utils::{format_units, parse_units, to_checksum};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use hex::encode;
+use std::{
+    borrow::Cow,
+    io::{Read as _, Write as _},
+};
 pub use web3::types::{
     Address, Bytes, Log, TransactionRequest, H128, H160, H2048, H256, U128, U256, U64,
 };
@@ -20,6 +26,30 @@ pub fn string_to_address(addr: String) -> Address {
         .expect(&format!("parse invalid address addr={}", addr))
 }
 
+/// EIP-55 checksummed form of `addr`, `0x` prefix is optional in input
+/// mixed-case input must carry a valid checksum, all lower/upper case is accepted as is
+pub fn normalize_address(addr: &str) -> Result<String> {
+    let trimmed = addr.trim();
+    let hex = trimmed
+        .strip_prefix("0x")
+        .or_else(|| trimmed.strip_prefix("0X"))
+        .unwrap_or(trimmed);
+    if hex.len() != 40 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!("invalid address addr={}", addr));
+    }
+    let checksummed = to_checksum(
+        &hex.parse::<ethers::types::Address>()
+            .map_err(|e| anyhow!("invalid address addr={} err={}", addr, e))?,
+        None,
+    );
+    let is_mixed_case =
+        hex.chars().any(|c| c.is_ascii_lowercase()) && hex.chars().any(|c| c.is_ascii_uppercase());
+    if is_mixed_case && checksummed[2..] != *hex {
+        return Err(anyhow!("invalid address checksum addr={}", addr));
+    }
+    Ok(checksummed)
+}
+
 pub fn u256_to_szabo(value: U256) -> i64 {
     format_units(value, "szabo")
         .unwrap()
@@ -30,3 +60,163 @@ pub fn u256_to_szabo(value: U256) -> i64 {
 pub fn szabo_to_u256(value: i64) -> U256 {
     U256::from(parse_units(value, "szabo").unwrap())
 }
+
+/// returns the longest prefix of `s` that is at most `max` bytes and ends on a char boundary
+pub fn truncate_str(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// shortens long ids (addresses, hashes, topics) to prefix...suffix for logging
+pub fn log_id(s: &str) -> String {
+    const PREFIX: usize = 6;
+    const SUFFIX: usize = 4;
+
+    let len = s.chars().count();
+    if len <= PREFIX + SUFFIX + 3 {
+        return s.to_string();
+    }
+    let prefix: String = s.chars().take(PREFIX).collect();
+    let suffix: String = s.chars().skip(len - SUFFIX).collect();
+    format!("{}...{}", prefix, suffix)
+}
+
+/// canonical id of a peer connection, the same for every casing/format of `peer_addr`
+/// an invalid `peer_addr` is only trimmed and lower-cased, so the id is still stable
+/// the peer queue and peer session indexes stay keyed on peer_addr, switching them to this id
+/// needs a migration of the existing redis keys, until then it is for joining peer events
+pub fn peer_identity(peer_addr: &str, login_session_id: &str) -> String {
+    let peer_addr =
+        normalize_address(peer_addr).unwrap_or_else(|_| peer_addr.trim().to_lowercase());
+    let id = hash::hash(format!("{}:{}", peer_addr, login_session_id).as_bytes());
+    bytes_to_hex_string(id.as_bytes())
+}
+
+/// gzip magic bytes, neither json nor protobuf (wire type 7 is invalid) payloads start with them
+/// so compressed and plain payloads are told apart without a header
+pub const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// gzip of `bz` when it is larger than `threshold` bytes, otherwise `bz` as is
+/// None never compresses
+pub fn gzip_above(bz: Vec<u8>, threshold: Option<usize>) -> Result<Vec<u8>> {
+    match threshold {
+        Some(threshold) if bz.len() > threshold => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+            encoder
+                .write_all(&bz)
+                .and_then(|_| encoder.finish())
+                .map_err(|e| anyhow!("gzip failed len={} err={}", bz.len(), e))
+        }
+        _ => Ok(bz),
+    }
+}
+
+/// inverse of `gzip_above`, `bz` without GZIP_MAGIC is returned as is
+pub fn gunzip(bz: &[u8]) -> Result<Cow<'_, [u8]>> {
+    if !bz.starts_with(&GZIP_MAGIC) {
+        return Ok(Cow::Borrowed(bz));
+    }
+    inflate(bz).map(Cow::Owned)
+}
+
+/// inflate gzipped `bz`, errors when it is not gzip
+pub fn inflate(bz: &[u8]) -> Result<Vec<u8>> {
+    let mut inflated = Vec::new();
+    GzDecoder::new(bz)
+        .read_to_end(&mut inflated)
+        .map_err(|e| anyhow!("gunzip failed len={} err={}", bz.len(), e))?;
+    Ok(inflated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate_str() {
+        assert_eq!(truncate_str("abc", 10), "abc");
+        assert_eq!(truncate_str("abcdef", 3), "abc");
+        assert_eq!(truncate_str("", 3), "");
+        // "é" takes 2 bytes, cutting in the middle must back off
+        assert_eq!(truncate_str("aé", 2), "a");
+        assert_eq!(truncate_str("日本語", 4), "日");
+        assert_eq!(truncate_str("日本語", 0), "");
+    }
+
+    #[test]
+    fn test_normalize_address() {
+        let checksummed = "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed";
+        assert_eq!(normalize_address(checksummed).unwrap(), checksummed);
+        assert_eq!(
+            normalize_address("5aaeb6053f3e94c9b9a09f33669435e7ef1beaed").unwrap(),
+            checksummed
+        );
+        assert_eq!(
+            normalize_address(" 0X5AAEB6053F3E94C9B9A09F33669435E7EF1BEAED ").unwrap(),
+            checksummed
+        );
+        assert!(normalize_address("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAeD").is_err());
+        assert!(normalize_address("0xpeer").is_err());
+    }
+
+    #[test]
+    fn test_peer_identity() {
+        let addr = "0x97979e98f99f0ba2fb61b5cf00f55c0f33d294f5";
+        let id = peer_identity(addr, "login_session_id");
+        assert_eq!(id.len(), 66);
+        assert_eq!(
+            id,
+            peer_identity(&addr.to_uppercase().replace("0X", "0x"), "login_session_id")
+        );
+        assert_eq!(id, peer_identity(&addr[2..], "login_session_id"));
+        assert_ne!(id, peer_identity(addr, "other_login_session_id"));
+        assert_eq!(
+            peer_identity("0xpeer", "login_session_id"),
+            peer_identity(" 0xPEER", "login_session_id")
+        );
+    }
+
+    #[test]
+    fn test_address_to_string() {
+        let checksummed = "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed";
+        let addr = checksummed.parse::<Address>().unwrap();
+        // stored keys are built from it, its lowercase form must not change
+        assert_eq!(address_to_string(addr), checksummed.to_lowercase());
+        assert_eq!(
+            normalize_address(&address_to_string(addr)).unwrap(),
+            checksummed
+        );
+    }
+
+    #[test]
+    fn test_gzip_above() {
+        let json = br#"{"user_addr":"0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed"}"#.to_vec();
+        assert_eq!(gzip_above(json.clone(), None).unwrap(), json);
+        assert_eq!(gzip_above(json.clone(), Some(json.len())).unwrap(), json);
+
+        let compressed = gzip_above(json.clone(), Some(8)).unwrap();
+        assert!(compressed.starts_with(&GZIP_MAGIC));
+        assert_eq!(gunzip(&compressed).unwrap(), json.as_slice());
+        assert_eq!(gunzip(&json).unwrap(), json.as_slice());
+        assert!(gunzip(&GZIP_MAGIC).is_err());
+    }
+
+    #[test]
+    fn test_log_id() {
+        assert_eq!(log_id("short"), "short");
+        assert_eq!(
+            log_id("0x97979e98f99f0ba2fb61b5cf00f55c0f33d294f5"),
+            "0x9797...94f5"
+        );
+        assert_eq!(
+            log_id("日本語日本語日本語日本語日本語"),
+            "日本語日本語...語日本語"
+        );
+    }
+}